@@ -1,133 +1,588 @@
+use crate::connection::SshCred;
+use crate::error::{Error, PathContext};
+use crate::file_util::list_local;
+use crate::options::SymlinkPolicy;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::io::{self, prelude::*, BufRead, Error};
-use std::{fs, path::Path, path::PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, prelude::*};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
 
-#[derive(Deserialize, Debug, Serialize)]
+///What the last sync recorded of a file
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    ///checksum prefixed with the hash that made it, or [`LINK_PREFIX`] and the target of a link
+    pub checksum: String,
+    ///stamp of the local copy, read before it was hashed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stamp: Option<Stamp>,
+}
+
+impl FileEntry {
+    pub fn new(checksum: &str) -> Self {
+        FileEntry {
+            checksum: checksum.to_string(),
+            stamp: None,
+        }
+    }
+}
+
+///What the last sync recorded of a folder, only that it exists for now
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct FolderEntry {}
+
+#[derive(Deserialize, Debug, Serialize, Default, Clone)]
 pub struct Config {
-    pub folders: HashMap<String, String>,
-    pub files: HashMap<String, String>,
+    #[serde(default)]
+    pub folders: HashMap<String, FolderEntry>,
+    #[serde(default)]
+    pub files: HashMap<String, FileEntry>,
+    //state of the syncs to each server, keyed by target_key. the maps above hold the state
+    //of pulls and clones, it is never used to push
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub targets: BTreeMap<String, Config>,
+}
+
+//`.xsync.toml` as it is written, the version of its layout comes first
+#[derive(Deserialize, Serialize)]
+struct ConfigFile {
+    version: i64,
+    #[serde(flatten)]
+    config: Config,
+}
+
+//`.xsync.toml` as written before it had a version, entries were plain strings
+//and the stamps of files a map of their own
+#[derive(Deserialize)]
+struct UnversionedConfig {
+    folders: HashMap<String, String>,
+    files: HashMap<String, String>,
+    #[serde(default)]
+    stats: HashMap<String, Stamp>,
+    #[serde(default)]
+    targets: BTreeMap<String, UnversionedConfig>,
+}
+
+impl From<UnversionedConfig> for Config {
+    fn from(old: UnversionedConfig) -> Self {
+        let stats = old.stats;
+        let files = old.files.into_iter().map(|(key, checksum)| {
+            let stamp = stats.get(&key).copied();
+            (key, FileEntry { checksum, stamp })
+        });
+        Config {
+            folders: old
+                .folders
+                .into_keys()
+                .map(|key| (key, FolderEntry::default()))
+                .collect(),
+            files: files.collect(),
+            targets: old
+                .targets
+                .into_iter()
+                .map(|(key, state)| (key, state.into()))
+                .collect(),
+        }
+    }
+}
+
+///Where the state of a sync is recorded
+///
+///dir holds `.xsync.toml`. Syncs to a server keep their state under the key of the target
+///so a folder synced to several servers tracks each of them, pulls and clones have no target.
+///Updates go to the config held by open when set, straight to the file otherwise
+#[derive(Debug, Clone, Copy)]
+pub struct Manifest<'a> {
+    pub dir: &'a Path,
+    pub target: Option<&'a str>,
+    pub open: Option<&'a OpenConfig>,
+}
+
+impl<'a> Manifest<'a> {
+    #[cfg(test)]
+    pub fn local(dir: &'a Path) -> Self {
+        Manifest {
+            dir,
+            target: None,
+            open: None,
+        }
+    }
+
+    //file or folder of the state dir kept for this target
+    pub fn state_path(&self, name: &str) -> PathBuf {
+        let state = self.dir.join(STATE_DIR);
+        match self.target {
+            None => state.join(name),
+            Some(target) => {
+                let hash = blake3::hash(target.as_bytes()).to_hex();
+                state.join(TARGETS_DIR).join(&hash[..16]).join(name)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Loaded {
+    whole: Config,
+    //updates not written to the file yet
+    unsaved: usize,
+}
+
+///The toml config of a folder, held in memory while a run updates it
+///
+///Opening it locks `.xsync/lock` so another run on the same folder fails at once with
+///[`Error::Locked`]. Updates are written in batches through a temp file renamed over
+///`.xsync.toml`, what is left is written when it is closed or dropped
+#[derive(Debug)]
+pub struct OpenConfig {
+    dir: PathBuf,
+    loaded: Mutex<Loaded>,
+    //the lock is held as long as the file is open
+    _lock: fs::File,
+}
+
+//lock the state dir of dir, the lock is released when the file is closed
+fn lock(dir: &Path) -> Result<fs::File, Error> {
+    let lock_path = dir.join(STATE_DIR).join(LOCK_FILE);
+    fs::create_dir_all(lock_path.parent().unwrap()).at(&lock_path)?;
+    let lock = fs::File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .at(&lock_path)?;
+    match lock.try_lock() {
+        Ok(()) => Ok(lock),
+        Err(fs::TryLockError::WouldBlock) => Err(Error::Locked { path: lock_path }),
+        Err(fs::TryLockError::Error(err)) => Err(err).at(&lock_path),
+    }
+}
+
+impl OpenConfig {
+    fn with(dir: &Path, whole: Config, lock: fs::File) -> Self {
+        OpenConfig {
+            dir: dir.to_path_buf(),
+            loaded: Mutex::new(Loaded { whole, unsaved: 0 }),
+            _lock: lock,
+        }
+    }
+
+    pub fn open(dir: &Path) -> Result<Self, Error> {
+        let lock = lock(dir)?;
+        Ok(OpenConfig::with(dir, load_whole_config(dir)?, lock))
+    }
+
+    //like open, but a config that can't be parsed is moved aside to `.xsync.toml.broken`
+    //and an empty one is started, returns where the broken one went
+    pub fn open_or_reset(dir: &Path) -> Result<(Self, Option<PathBuf>), Error> {
+        let lock = lock(dir)?;
+        match load_whole_config(dir) {
+            Ok(whole) => Ok((OpenConfig::with(dir, whole, lock), None)),
+            Err(Error::Manifest { .. }) => {
+                let folder_path = checksum_file_path(dir);
+                let broken = PathBuf::from(format!("{}{}", folder_path.display(), BROKEN_SUFFIX));
+                fs::rename(&folder_path, &broken).at(&folder_path)?;
+                let config = OpenConfig::with(dir, Config::default(), lock);
+                //written at once so the folder doesn't stay without a config
+                config.update(|_| Ok(()))?;
+                config.flush()?;
+                Ok((config, Some(broken)))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn local(&self) -> Manifest<'_> {
+        Manifest {
+            dir: &self.dir,
+            target: None,
+            open: Some(self),
+        }
+    }
+
+    pub fn remote<'a>(&'a self, target: &'a str) -> Manifest<'a> {
+        Manifest {
+            dir: &self.dir,
+            target: Some(target),
+            open: Some(self),
+        }
+    }
+
+    fn update(&self, apply: impl FnOnce(&mut Config) -> Result<(), Error>) -> Result<(), Error> {
+        let mut loaded = self.loaded.lock().unwrap();
+        apply(&mut loaded.whole)?;
+        loaded.unsaved += 1;
+        if loaded.unsaved >= FLUSH_EVERY {
+            write_config(&self.dir, &loaded.whole)?;
+            loaded.unsaved = 0;
+        }
+        Ok(())
+    }
+
+    //forget everything recorded for target, or the state without a target when None
+    pub fn reset(&self, target: Option<&str>) -> Result<(), Error> {
+        self.update(|whole| {
+            match target {
+                Some(target) => {
+                    whole.targets.insert(target.to_string(), Config::default());
+                }
+                None => {
+                    let targets = std::mem::take(&mut whole.targets);
+                    *whole = Config {
+                        targets,
+                        ..Config::default()
+                    };
+                }
+            }
+            Ok(())
+        })?;
+        self.flush()
+    }
+
+    pub fn flush(&self) -> Result<(), Error> {
+        let mut loaded = self.loaded.lock().unwrap();
+        if loaded.unsaved > 0 {
+            write_config(&self.dir, &loaded.whole)?;
+            loaded.unsaved = 0;
+        }
+        Ok(())
+    }
+
+    ///write the updates left and release the lock
+    pub fn close(self) -> Result<(), Error> {
+        self.flush()
+    }
+}
+
+impl Drop for OpenConfig {
+    //a run stopped by an error keeps what it recorded before
+    fn drop(&mut self) {
+        self.flush().ok();
+    }
+}
+
+///key of the state of the syncs sending to dest on the server of ssh
+pub fn target_key(ssh: &SshCred, dest: &Path) -> String {
+    format!("{}:{}", ssh.addr(), dest.display())
+}
+
+//the target of the syncs to dest, when a single server got them
+pub fn target_for_dest(dir: &Path, dest: &Path) -> Result<Option<String>, Error> {
+    let suffix = format!(":{}", dest.display());
+    let config = load_whole_config(dir)?;
+    //what is left of a key is the `host:port` of the server, an IPv6 host has colons too
+    let mut keys = config.targets.into_keys().filter(|key| {
+        key.strip_suffix(&suffix)
+            .and_then(|addr| addr.rsplit_once(':'))
+            .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+    });
+    match (keys.next(), keys.next()) {
+        (key, None) => Ok(key),
+        (Some(_), Some(_)) => Err(Error::Manifest {
+            path: checksum_file_path(dir),
+            message: format!("{:?} was synced on several servers, name the server", dest),
+        }),
+        (None, Some(_)) => unreachable!(),
+    }
 }
 
+///size and modification time in nanoseconds of a local file
+pub type Stamp = [u64; 2];
+
 #[derive(Debug)]
 pub enum FolderConfig {
+    //a folder, its value is not recorded, or a file without a stamp
     Add(String, String),
+    //a file with the stamp of its local copy, read before it was hashed
+    AddFile(String, String, Option<Stamp>),
     Remove(String),
 }
 
 pub const CHECKSUM_FILE: &str = ".xsync.toml";
 pub const IGNORE_FILE: &str = ".xsyncignore";
+///start of the value recorded in the toml config for a symbolic link, followed by its target
+pub const LINK_PREFIX: &str = "link:";
+///directory next to the toml config holding extra sync state
+pub const STATE_DIR: &str = ".xsync";
+///end of the hidden name a file is written to before it replaces its target
+pub const TEMP_SUFFIX: &str = ".xsync-tmp";
+//file of the state dir locked while a run updates the toml config
+const LOCK_FILE: &str = "lock";
+//added to the name of a toml config that couldn't be parsed when it is replaced
+const BROKEN_SUFFIX: &str = ".broken";
+///version of the layout of `.xsync.toml` written by this version of rxsync,
+///files without a version are from before it had one
+pub const MANIFEST_VERSION: i64 = 2;
+//updates held in memory before the toml config is written again
+const FLUSH_EVERY: usize = 256;
+//folder of the state dir holding the state of each target
+const TARGETS_DIR: &str = "targets";
+
+//hidden name in the same folder a file is written to before it is renamed over path,
+//so the rename stays on one filesystem
+pub fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap().to_str().unwrap();
+    path.with_file_name(format!(".{}{}", name, TEMP_SUFFIX))
+}
+
+pub fn checksum_file_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}/{}", path.to_str().unwrap(), CHECKSUM_FILE))
+}
 
 pub fn create_checksum_file(path: &Path) -> Result<(), Error> {
-    let folder_path = format!("{}/{}", path.to_str().unwrap(), CHECKSUM_FILE);
-    if !Path::new(&folder_path).exists() {
-        let mut file = fs::File::create(folder_path)?;
-        let config = Config {
-            folders: HashMap::new(),
-            files: HashMap::new(),
-        };
-        let toml = toml::to_string(&config).unwrap();
-        file.write_all(toml.as_bytes())?;
+    let folder_path = checksum_file_path(path);
+    if !folder_path.exists() {
+        write_config(path, &Config::default())?;
     }
     Ok(())
 }
 
-pub fn get_ignore_file(path: &Path) -> Result<Vec<String>, Error> {
-    let folder_path = format!("{}/{}", path.to_str().unwrap(), IGNORE_FILE);
-    let mut all_lines = vec![];
-    if Path::new(&folder_path).exists() {
-        if let Ok(lines) = read_lines(folder_path) {
-            for line in lines {
-                let str_path = path.to_str().unwrap();
-                if str_path.starts_with("./") {
-                    let result = str_path.replace("./", "");
-                    let resolved_path = Path::new("").join(result).join(line?);
-                    all_lines.push(String::from(resolved_path.to_str().unwrap()))
-                } else {
-                    let resolved_path = Path::new("").join(path).join(line?);
-                    all_lines.push(String::from(resolved_path.to_str().unwrap()))
-                }
+//write the toml config of dir through a temp file renamed over it
+//so a crash leaves either the old or the new config, never a partial one
+fn write_config(dir: &Path, config: &Config) -> Result<(), Error> {
+    let folder_path = checksum_file_path(dir);
+    let temp = temp_path(&folder_path);
+    let toml = to_toml(config, &folder_path)?;
+    let mut file = fs::File::create(&temp).at(&temp)?;
+    file.write_all(toml.as_bytes()).at(&temp)?;
+    file.sync_all().at(&temp)?;
+    fs::rename(&temp, &folder_path).at(&folder_path)
+}
+
+///Rules read from every `.xsyncignore` file under a folder, matched like `.gitignore` files
+///
+///A file in a sub folder applies to that folder's content and takes precedence over its parents
+#[derive(Default)]
+pub struct IgnoreRules {
+    //folder each ignore file is in, deepest last
+    rules: Vec<(PathBuf, Gitignore)>,
+}
+
+//drop `./` so paths given either way compare equal
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
+impl IgnoreRules {
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let path = normalize(path);
+        //like git, nothing inside an ignored folder can be included again
+        let mut parents: Vec<&Path> = path.ancestors().skip(1).collect();
+        parents.reverse();
+        parents.into_iter().any(|parent| self.matched(parent, true)) || self.matched(&path, is_dir)
+    }
+
+    //the closest ignore file with a rule for path decides
+    fn matched(&self, path: &Path, is_dir: bool) -> bool {
+        for (dir, rules) in self.rules.iter().rev() {
+            let relative = match path.strip_prefix(dir) {
+                Ok(relative) if !relative.as_os_str().is_empty() => relative,
+                _ => continue,
+            };
+            match rules.matched(relative, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
             }
-            return Ok(all_lines);
         }
+        false
     }
-    Ok(vec![])
 }
 
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<fs::File>>>
-where
-    P: AsRef<Path>,
-{
-    let file = fs::File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+//extra rules are matched like lines of an ignore file in path, after the ones it has
+pub fn get_ignore_file(path: &Path, extra: &[String]) -> Result<IgnoreRules, Error> {
+    //links are not followed so they can't loop back into the folder
+    let mut folders = list_local(path, SymlinkPolicy::Skip)?.folders;
+    folders.push(path.to_path_buf());
+    let mut rules = vec![];
+    for folder in folders {
+        let file = folder.join(IGNORE_FILE);
+        if file.is_file() {
+            rules.push(read_ignore_file(&file)?);
+        }
+    }
+    if !extra.is_empty() {
+        let lines = extra.iter().map(String::as_str);
+        rules.push(build_rules(path, lines, path)?);
+    }
+    rules.sort_by_key(|(dir, _)| dir.components().count());
+    Ok(IgnoreRules { rules })
+}
+
+fn read_ignore_file(path: &Path) -> Result<(PathBuf, Gitignore), Error> {
+    let data = fs::read_to_string(path).map_err(|source| Error::Ignore {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    build_rules(path.parent().unwrap(), data.lines(), path)
+}
+
+//source is the file the lines come from, for errors
+fn build_rules<'a>(
+    dir: &Path,
+    lines: impl Iterator<Item = &'a str>,
+    source: &Path,
+) -> Result<(PathBuf, Gitignore), Error> {
+    let invalid = |err: ignore::Error| Error::Ignore {
+        path: source.to_path_buf(),
+        source: io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+    };
+    //patterns are matched against paths relative to the folder of the ignore file
+    let mut builder = GitignoreBuilder::new("");
+    for line in lines {
+        builder
+            .add_line(Some(source.to_path_buf()), line)
+            .map_err(invalid)?;
+    }
+    let rules = builder.build().map_err(invalid)?;
+    Ok((normalize(dir), rules))
 }
 
 pub fn read_checksum_file(path: &Path) -> Result<String, Error> {
-    let folder_path = format!("{}/{}", path.to_str().unwrap(), CHECKSUM_FILE);
-    let data = fs::read_to_string(folder_path)?;
-    Ok(data)
+    let folder_path = checksum_file_path(path);
+    fs::read_to_string(&folder_path).at(&folder_path)
 }
 
-pub fn parse_checksum_config(data: &String) -> Result<Config, String> {
-    let raw_cfg: Result<Config, toml::de::Error> = toml::from_str(&data);
-    let raw_cfg = match raw_cfg {
-        Ok(raw_cfg) => raw_cfg,
-        Err(_) => return Err(String::from("Error parsing config")),
+//state of the manifest's target in its toml config, empty if none was written yet
+pub fn load_checksum_config(manifest: Manifest) -> Result<Config, Error> {
+    let mut config = match manifest.open {
+        Some(open) => open.loaded.lock().unwrap().whole.clone(),
+        None => load_whole_config(manifest.dir)?,
     };
-    Ok(raw_cfg)
+    match manifest.target {
+        Some(target) => Ok(config.targets.remove(target).unwrap_or_default()),
+        None => {
+            config.targets.clear();
+            Ok(config)
+        }
+    }
+}
+
+fn load_whole_config(path: &Path) -> Result<Config, Error> {
+    let folder_path = checksum_file_path(path);
+    if !folder_path.exists() {
+        return Ok(Config::default());
+    }
+    parse_checksum_config(&read_checksum_file(path)?, &folder_path)
+}
+
+//path is the toml file the data was read from, used for error reporting
+//a config written by an older version is moved to the current layout, it is saved that way
+//with the next update
+pub fn parse_checksum_config(data: &str, path: &Path) -> Result<Config, Error> {
+    let invalid = |message: String| Error::Manifest {
+        path: path.to_path_buf(),
+        message,
+    };
+    let value: toml::Value = toml::from_str(data).map_err(|err| invalid(err.to_string()))?;
+    let version = match value.get("version") {
+        None => 1,
+        Some(version) => version
+            .as_integer()
+            .ok_or_else(|| invalid(format!("invalid version {}", version)))?,
+    };
+    match version {
+        1 => value
+            .try_into::<UnversionedConfig>()
+            .map(Config::from)
+            .map_err(|err| invalid(err.to_string())),
+        MANIFEST_VERSION => value
+            .try_into::<ConfigFile>()
+            .map(|file| file.config)
+            .map_err(|err| invalid(err.to_string())),
+        _ => Err(invalid(format!(
+            "version {} is not supported, this rxsync reads up to version {}",
+            version, MANIFEST_VERSION
+        ))),
+    }
+}
+
+fn to_toml(config: &Config, path: &Path) -> Result<String, Error> {
+    let file = ConfigFile {
+        version: MANIFEST_VERSION,
+        config: config.clone(),
+    };
+    toml::to_string(&file).map_err(|err| Error::Manifest {
+        path: path.to_path_buf(),
+        message: err.to_string(),
+    })
 }
 
 pub fn update_folder_config(
     key_config: &str,
-    path: &Path,
+    manifest: Manifest,
+    action: &FolderConfig,
+) -> Result<(), Error> {
+    match manifest.open {
+        Some(open) => open.update(|whole| apply_update(whole, key_config, manifest, action)),
+        None => {
+            let folder_path = checksum_file_path(manifest.dir);
+            let cfg_data = read_checksum_file(manifest.dir)?;
+            let mut whole = parse_checksum_config(&cfg_data, &folder_path)?;
+            apply_update(&mut whole, key_config, manifest, action)?;
+            write_config(manifest.dir, &whole)
+        }
+    }
+}
+
+//apply an update to the state of the manifest's target in the whole toml config
+fn apply_update(
+    whole: &mut Config,
+    key_config: &str,
+    manifest: Manifest,
     action: &FolderConfig,
 ) -> Result<(), Error> {
-    let folder_path = format!("{}/{}", path.to_str().unwrap(), CHECKSUM_FILE);
-    let cfg_data = read_checksum_file(path)?;
-    let mut a = parse_checksum_config(&cfg_data).unwrap();
+    let folder_path = checksum_file_path(manifest.dir);
+    let a = match manifest.target {
+        None => whole,
+        Some(target) => whole.targets.entry(target.to_string()).or_default(),
+    };
 
     if (key_config != "folders") && (key_config != "files") {
-        println!("invalid key input");
-        return Ok(());
-    }
-    match action {
-        FolderConfig::Add(key, value) => {
-            if key_config == "folders" {
-                a.folders.insert(key.to_string(), value.to_string());
-                let toml_str = toml::to_string(&a).unwrap();
-                fs::write(&folder_path, &toml_str)?;
-            } else {
-                a.files.insert(key.to_string(), value.to_string());
-                let toml_str = toml::to_string(&a).unwrap();
-                fs::write(&folder_path, &toml_str)?;
-            }
-            Ok(())
+        return Err(Error::Manifest {
+            path: folder_path,
+            message: format!("invalid key input {}", key_config),
+        });
+    }
+    match (key_config, action) {
+        ("folders", FolderConfig::Add(key, _) | FolderConfig::AddFile(key, _, _)) => {
+            a.folders.insert(key.to_string(), FolderEntry::default());
         }
-        FolderConfig::Remove(item) => {
-            if key_config == "folders" {
-                a.folders.remove(item);
-                let toml_str = toml::to_string(&a).unwrap();
-                fs::write(&folder_path, &toml_str)?;
-            } else {
-                a.files.remove(item);
-                let toml_str = toml::to_string(&a).unwrap();
-                fs::write(&folder_path, &toml_str)?;
-            }
-
-            Ok(())
+        ("folders", FolderConfig::Remove(key)) => {
+            a.folders.remove(key);
+        }
+        //a stamp left from an older version of the file would skip the next comparison
+        (_, FolderConfig::Add(key, checksum)) => {
+            a.files.insert(key.to_string(), FileEntry::new(checksum));
+        }
+        (_, FolderConfig::AddFile(key, checksum, stamp)) => {
+            let entry = FileEntry {
+                checksum: checksum.to_string(),
+                stamp: *stamp,
+            };
+            a.files.insert(key.to_string(), entry);
+        }
+        (_, FolderConfig::Remove(key)) => {
+            a.files.remove(key);
         }
     }
+    Ok(())
 }
 
-pub fn get_items_to_delete(
-    config_state: &HashMap<String, String>,
-    item_list: &Vec<PathBuf>,
+pub fn get_items_to_delete<V>(
+    config_state: &HashMap<String, V>,
+    item_list: &[PathBuf],
 ) -> Vec<String> {
     let mut return_vec: Vec<String> = vec![];
     //if an item exist on the config state
     //but no longer on the item list
     //mark as delete
-    for (key, _) in config_state.into_iter() {
+    for key in config_state.keys() {
         if !item_list.contains(&PathBuf::from(key)) {
             return_vec.push(key.to_string())
         }
@@ -135,9 +590,9 @@ pub fn get_items_to_delete(
     return_vec
 }
 
-pub fn get_items_to_upload(
-    config_state: &HashMap<String, String>,
-    item_list: &Vec<PathBuf>,
+pub fn get_items_to_upload<V>(
+    config_state: &HashMap<String, V>,
+    item_list: &[PathBuf],
 ) -> Vec<String> {
     //if an item exist in memory but not config state
     //mark to upload
@@ -153,7 +608,6 @@ pub fn get_items_to_upload(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::File;
 
     #[test]
     fn test_create_checksum_file() {
@@ -164,21 +618,46 @@ mod tests {
             }
             Err(err) => {
                 println!("error creating checksum file {:?}", err);
-                assert!(false)
+                panic!()
             }
         }
     }
 
     #[test]
     fn test_get_ignore_file() {
-        let path = format!("test_sync/{}", IGNORE_FILE);
-        //create ignore file
-        let mut file = File::create(&path).expect("Error encountered while creating file!");
-        file.write_all(b"/test_dir")
-            .expect("Error while writing to file");
-        let data = get_ignore_file(Path::new("test_sync")).unwrap();
-        assert!(data.contains(&"/test_dir".to_string()));
-        std::fs::remove_file(path).unwrap();
+        let root = Path::new("test_ignore");
+        fs::create_dir_all(root.join("sub/node_modules")).unwrap();
+        fs::write(
+            root.join(IGNORE_FILE),
+            "# comment\n\n*.log\n!keep.log\n/build\ncache/\n**/node_modules\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("sub").join(IGNORE_FILE),
+            "*.tmp\n!keep.log\nkeep.log\n",
+        )
+        .unwrap();
+        let rules =
+            get_ignore_file(root, &["!debug.log".to_string(), "*.bak".to_string()]).unwrap();
+        fs::remove_dir_all(root).unwrap();
+
+        //extra rules come after the root file
+        assert!(!rules.is_ignored(Path::new("test_ignore/debug.log"), false));
+        assert!(rules.is_ignored(Path::new("test_ignore/other.log"), false));
+        assert!(rules.is_ignored(Path::new("test_ignore/sub/a.bak"), false));
+        assert!(!rules.is_ignored(Path::new("test_ignore/keep.log"), false));
+        assert!(!rules.is_ignored(Path::new("test_ignore/# comment"), false));
+        //anchored to the folder of the ignore file
+        assert!(rules.is_ignored(Path::new("./test_ignore/build"), true));
+        assert!(!rules.is_ignored(Path::new("test_ignore/sub/build"), true));
+        //trailing slash only matches folders
+        assert!(rules.is_ignored(Path::new("test_ignore/sub/cache/file"), false));
+        assert!(!rules.is_ignored(Path::new("test_ignore/cache"), false));
+        assert!(rules.is_ignored(Path::new("test_ignore/sub/node_modules/lib.js"), false));
+        //rules in a sub folder only apply there and override the parent
+        assert!(rules.is_ignored(Path::new("test_ignore/sub/a.tmp"), false));
+        assert!(!rules.is_ignored(Path::new("test_ignore/a.tmp"), false));
+        assert!(rules.is_ignored(Path::new("test_ignore/sub/keep.log"), false));
     }
 
     #[test]
@@ -191,31 +670,202 @@ mod tests {
 
         update_folder_config(
             "files",
-            Path::new("test_sync"),
+            Manifest::local(Path::new("test_sync")),
             &FolderConfig::Add("test".to_string(), "123".to_string()),
         )
         .unwrap();
 
         let data = read_checksum_file(Path::new("test_sync")).unwrap();
-        let parsed_config = parse_checksum_config(&data).unwrap();
+        let parsed_config = parse_checksum_config(&data, Path::new(&path)).unwrap();
 
         assert!(parsed_config.files.contains_key("test"));
 
         update_folder_config(
             "files",
-            Path::new("test_sync"),
+            Manifest::local(Path::new("test_sync")),
             &FolderConfig::Remove("test".to_string()),
         )
         .unwrap();
 
         let data = read_checksum_file(Path::new("test_sync")).unwrap();
-        let parsed_config = parse_checksum_config(&data).unwrap();
+        let parsed_config = parse_checksum_config(&data, Path::new(&path)).unwrap();
 
         assert!(!parsed_config.files.contains_key("test"));
 
         std::fs::remove_file(path).unwrap();
     }
 
+    #[test]
+    fn test_targets() {
+        let dir = Path::new("test_targets");
+        fs::create_dir_all(dir).unwrap();
+        create_checksum_file(dir).unwrap();
+        let add = |manifest, key: &str| {
+            let action = FolderConfig::Add(key.to_string(), "1".to_string());
+            update_folder_config("files", manifest, &action).unwrap();
+        };
+        //recorded by a pull before any push
+        add(Manifest::local(dir), "pulled");
+        let config = OpenConfig::open(dir).unwrap();
+        let staging = config.remote("staging:22:www");
+        let first_push = load_checksum_config(staging).unwrap();
+        add(staging, "staged");
+        add(config.remote("production:22:www"), "released");
+        add(config.local(), "pulled again");
+        config.close().unwrap();
+
+        let config = OpenConfig::open(dir).unwrap();
+        let staged = load_checksum_config(config.remote("staging:22:www")).unwrap();
+        let released = load_checksum_config(config.remote("production:22:www")).unwrap();
+        let local = load_checksum_config(Manifest::local(dir)).unwrap();
+        drop(config);
+        let found = target_for_dest(dir, Path::new("www"));
+        let other = target_for_dest(dir, Path::new("other")).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        //the state of pulls is never handed to a push
+        assert!(first_push.files.is_empty());
+        let keys = |config: &Config| {
+            let mut keys: Vec<String> = config.files.keys().cloned().collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(keys(&staged), vec!["staged"]);
+        assert_eq!(keys(&released), vec!["released"]);
+        assert_eq!(keys(&local), vec!["pulled", "pulled again"]);
+        assert!(found.is_err());
+        assert_eq!(other, None);
+    }
+
+    #[test]
+    fn test_target_for_dest_ipv6() {
+        let dir = Path::new("test_target_for_dest_ipv6");
+        fs::create_dir_all(dir).unwrap();
+        create_checksum_file(dir).unwrap();
+        let config = OpenConfig::open(dir).unwrap();
+        for target in ["[::1]:2222:www", "staging:22:docs:www"] {
+            let action = FolderConfig::Add("file".to_string(), "1".to_string());
+            update_folder_config("files", config.remote(target), &action).unwrap();
+        }
+        config.close().unwrap();
+
+        let www = target_for_dest(dir, Path::new("www")).unwrap();
+        let docs = target_for_dest(dir, Path::new("docs:www")).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        //a dest holding a colon doesn't pass for a server syncing www
+        assert_eq!(www.as_deref(), Some("[::1]:2222:www"));
+        assert_eq!(docs.as_deref(), Some("staging:22:docs:www"));
+    }
+
+    #[test]
+    fn test_open_config() {
+        let dir = Path::new("test_open_config");
+        fs::create_dir_all(dir).unwrap();
+        create_checksum_file(dir).unwrap();
+        let config = OpenConfig::open(dir).unwrap();
+        let second = OpenConfig::open(dir);
+        let action = FolderConfig::Add("file".to_string(), "1".to_string());
+        update_folder_config("files", config.local(), &action).unwrap();
+        let before_close = load_whole_config(dir).unwrap();
+        let in_memory = load_checksum_config(config.local()).unwrap();
+        config.close().unwrap();
+        let after_close = load_whole_config(dir).unwrap();
+        let reopened = OpenConfig::open(dir).map(drop);
+        let temp_left = temp_path(&checksum_file_path(dir)).exists();
+        fs::remove_dir_all(dir).unwrap();
+
+        //a second run on the same folder fails until the first one is done
+        assert!(matches!(second, Err(Error::Locked { .. })));
+        assert!(reopened.is_ok());
+        //updates are written in batches, the last one when the config is closed
+        assert!(before_close.files.is_empty());
+        assert!(in_memory.files.contains_key("file"));
+        assert!(after_close.files.contains_key("file"));
+        assert!(!temp_left);
+    }
+
+    #[test]
+    fn test_open_or_reset() {
+        let dir = Path::new("test_open_or_reset");
+        fs::create_dir_all(dir).unwrap();
+        fs::write(checksum_file_path(dir), "folders = [").unwrap();
+        let (config, moved_aside) = OpenConfig::open_or_reset(dir).unwrap();
+        let action = FolderConfig::Add("file".to_string(), "1".to_string());
+        update_folder_config("files", config.remote("host:22:www"), &action).unwrap();
+        update_folder_config("files", config.remote("host:22:old"), &action).unwrap();
+        config.reset(Some("host:22:www")).unwrap();
+        let kept = load_checksum_config(config.remote("host:22:old")).unwrap();
+        let reset = load_checksum_config(config.remote("host:22:www")).unwrap();
+        config.close().unwrap();
+        let broken = fs::read_to_string(dir.join(".xsync.toml.broken"));
+        let (_, reopened) = OpenConfig::open_or_reset(dir).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        //the broken config is kept for a look and an empty one takes its place
+        assert_eq!(moved_aside, Some(dir.join(".xsync.toml.broken")));
+        assert_eq!(broken.unwrap(), "folders = [");
+        assert_eq!(reopened, None);
+        //only the state of the reset target is dropped
+        assert!(reset.files.is_empty());
+        assert!(kept.files.contains_key("file"));
+    }
+
+    #[test]
+    fn test_migrate_unversioned_config() {
+        let path = Path::new("old/.xsync.toml");
+        let old = r#"
+            [folders]
+            "old/" = ""
+            [files]
+            "old/file" = "blake3:ab"
+            "old/link" = "link:file"
+            [stats]
+            "old/file" = [2, 7]
+            [targets."host:22:www".folders]
+            [targets."host:22:www".files]
+            "old/sent" = "1234"
+        "#;
+        let config = parse_checksum_config(old, path).unwrap();
+        let written = to_toml(&config, path).unwrap();
+        let reread = parse_checksum_config(&written, path).unwrap();
+        let newer = format!("version = {}\n", MANIFEST_VERSION + 1);
+
+        //each file takes its stamp along, files without one have none
+        let file = FileEntry {
+            checksum: "blake3:ab".to_string(),
+            stamp: Some([2, 7]),
+        };
+        assert_eq!(config.files["old/file"], file);
+        assert_eq!(config.files["old/link"], FileEntry::new("link:file"));
+        assert!(config.folders.contains_key("old/"));
+        assert_eq!(
+            config.targets["host:22:www"].files["old/sent"],
+            FileEntry::new("1234")
+        );
+        //written back with the current version
+        assert!(written.starts_with(&format!("version = {}", MANIFEST_VERSION)));
+        assert_eq!(reread.files, config.files);
+        assert_eq!(reread.folders, config.folders);
+        assert_eq!(
+            reread.targets["host:22:www"].files,
+            config.targets["host:22:www"].files
+        );
+        assert!(matches!(
+            parse_checksum_config(&newer, path),
+            Err(Error::Manifest { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_checksum_config_error() {
+        let path = Path::new("broken/.xsync.toml");
+        match parse_checksum_config("folders = [", path) {
+            Err(Error::Manifest { path: err_path, .. }) => assert_eq!(err_path, path),
+            other => panic!("expected manifest error, got {:?}", other),
+        }
+    }
+
     #[test]
     fn test_get_items_to_delete() {
         // create
@@ -224,7 +874,7 @@ mod tests {
         // insert data
         sample.insert("one".to_string(), "1".to_string());
         sample.insert("two".to_string(), "2".to_string());
-        let delete_list = get_items_to_delete(&sample, &vec![Path::new("one").to_path_buf()]);
+        let delete_list = get_items_to_delete(&sample, &[Path::new("one").to_path_buf()]);
         assert!(delete_list.contains(&"two".to_string()));
     }
 
@@ -237,7 +887,7 @@ mod tests {
         sample.insert("one".to_string(), "1".to_string());
         sample.insert("two".to_string(), "2".to_string());
 
-        let upload_list = get_items_to_upload(&sample, &vec![Path::new("three").to_path_buf()]);
+        let upload_list = get_items_to_upload(&sample, &[Path::new("three").to_path_buf()]);
         assert!(upload_list.contains(&"three".to_string()));
     }
 }
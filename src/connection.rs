@@ -1,43 +1,67 @@
+use crate::error::Error;
 use ssh2::Session;
-use std::path::Path;
-use std::{io::Error, net::TcpStream};
+use std::net::TcpStream;
+use std::path::PathBuf;
 
-pub struct SshCred<'a> {
-    auth: AuthOption<'a>,
+pub struct SshCred {
+    auth: AuthOption,
     host: String,
     port: String,
 }
 
 ///Auth options for ssh credentials
-pub enum AuthOption<'a> {
+pub enum AuthOption {
     ///Attempt basic password authentication.
     UserauthPassword(String, String),
     ///authenticate the current connection with the first public key found in an SSH agent
     UserauthAgent(String),
     ///Attempt public key authentication using a PEM encoded private key file stored on disk.
-    UserauthPubkeyFile(String, Option<&'a Path>, &'a Path, Option<&'a str>),
+    UserauthPubkeyFile(String, Option<PathBuf>, PathBuf, Option<String>),
 }
 
-impl SshCred<'static> {
-    pub fn new(auth: AuthOption<'static>, host: String, port: String) -> Self {
+impl SshCred {
+    pub fn new(auth: AuthOption, host: String, port: String) -> Self {
         SshCred { auth, host, port }
     }
 
+    //an IPv6 host is put in brackets so its colons aren't taken for the port
+    pub(crate) fn addr(&self) -> String {
+        match self.host.contains(':') {
+            true => format!("[{}]:{}", self.host, self.port),
+            false => format!("{}:{}", self.host, self.port),
+        }
+    }
+
     pub fn connect(&self) -> Result<Session, Error> {
-        let url_host = format!("{}:{}", self.host, self.port);
-        let tcp = TcpStream::connect(url_host)?;
-        let mut sess = Session::new()?;
+        let url_host = self.addr();
+        let connection_error = |source: std::io::Error| Error::Connection {
+            addr: url_host.clone(),
+            source,
+        };
+        let tcp = TcpStream::connect(&url_host).map_err(connection_error)?;
+        let mut sess = Session::new().map_err(|err| connection_error(err.into()))?;
         sess.set_tcp_stream(tcp);
-        sess.handshake()?;
-        match &self.auth {
-            AuthOption::UserauthAgent(username) => sess.userauth_agent(&username)?,
+        sess.handshake()
+            .map_err(|err| connection_error(err.into()))?;
+        let (username, result) = match &self.auth {
+            AuthOption::UserauthAgent(username) => (username, sess.userauth_agent(username)),
             AuthOption::UserauthPassword(username, password) => {
-                sess.userauth_password(&username, &password)?
-            }
-            AuthOption::UserauthPubkeyFile(username, pubkey, privatekey, passphrase) => {
-                sess.userauth_pubkey_file(&username, *pubkey, privatekey, *passphrase)?
+                (username, sess.userauth_password(username, password))
             }
+            AuthOption::UserauthPubkeyFile(username, pubkey, privatekey, passphrase) => (
+                username,
+                sess.userauth_pubkey_file(
+                    username,
+                    pubkey.as_deref(),
+                    privatekey,
+                    passphrase.as_deref(),
+                ),
+            ),
         };
+        result.map_err(|source| Error::Authentication {
+            user: username.to_string(),
+            source,
+        })?;
         Ok(sess)
     }
 }
@@ -45,8 +69,22 @@ impl SshCred<'static> {
 #[cfg(test)]
 mod tests {
     use super::{AuthOption, SshCred};
+    use crate::error::Error;
     use std::env;
 
+    #[test]
+    fn test_connection_error() {
+        let ssh = SshCred::new(
+            AuthOption::UserauthAgent("user".to_string()),
+            "127.0.0.1".to_string(),
+            "1".to_string(),
+        );
+        match ssh.connect() {
+            Err(Error::Connection { addr, .. }) => assert_eq!(addr, "127.0.0.1:1"),
+            _ => panic!("expected connection error"),
+        }
+    }
+
     #[test]
     fn test_create_tcp_connection() {
         match env::var_os("NAME") {
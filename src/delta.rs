@@ -0,0 +1,551 @@
+//! Block level delta between the copy of a file on the server and its local version
+//!
+//! A signature holds a weak rolling checksum (Adler-32) and a strong hash (BLAKE3)
+//! for every block of the remote copy. When a file changes, a window the size of a
+//! block slides over the local version one byte at a time and the weak checksum is
+//! rolled along with it, the strong hash is only computed when the weak one matches
+//! a block of the remote copy. So a block is found again even when bytes were
+//! inserted or removed before it.
+//!
+//! The file is rebuilt in a copy of the remote file made on the server. Blocks found
+//! at another offset are copied there by the server with `dd`, the bytes that match
+//! no block are written with positioned SFTP writes, the copy is then truncated or
+//! extended to its new length. A server whose `dd` can't copy by byte offset gets the
+//! moved blocks sent again.
+
+use adler::adler32_slice;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{prelude::*, Error, ErrorKind};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Manifest, Stamp};
+use crate::error::PathContext;
+
+const SIGNATURE_MAGIC: &[u8; 4] = b"XSIG";
+const SIGNATURE_VERSION: u8 = 2;
+const MIN_BLOCK_SIZE: u64 = 4 * 1024;
+const MAX_BLOCK_SIZE: u64 = 128 * 1024;
+const READ_SIZE: usize = 256 * 1024;
+//modulo of the Adler-32 sums
+const ADLER_MOD: u64 = 65521;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: [u8; 32],
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Signature {
+    pub block_size: u32,
+    pub file_len: u64,
+    ///BLAKE3 of the whole file, what `b3sum` prints for the remote copy
+    pub file_hash: [u8; 32],
+    ///size and mtime of the remote copy once uploaded, a copy that still has them isn't hashed
+    pub remote_stamp: Option<Stamp>,
+    pub blocks: Vec<BlockSignature>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Delta {
+    ///signature of the new version of the file
+    pub signature: Signature,
+    ///byte ranges of the new version that match no block of the remote copy
+    pub changed: Vec<Range<u64>>,
+    ///byte ranges of the new version found at another offset of the remote copy
+    pub moved: Vec<Moved>,
+}
+
+///a run of blocks of the remote copy that starts at `from` and goes to `to` in the new version
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Moved {
+    pub from: u64,
+    pub to: Range<u64>,
+}
+
+///pick a block size from the file length, roughly the square root like rsync does
+pub fn block_size_for(file_len: u64) -> u32 {
+    let root = (file_len as f64).sqrt() as u64;
+    let rounded = root.div_ceil(1024) * 1024;
+    rounded.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE) as u32
+}
+
+fn block_signature(block: &[u8]) -> BlockSignature {
+    BlockSignature {
+        weak: adler32_slice(block),
+        strong: *blake3::hash(block).as_bytes(),
+    }
+}
+
+//Adler-32 of a window that slides over the data one byte at a time
+struct RollingChecksum {
+    a: u64,
+    b: u64,
+    len: u64,
+}
+
+impl RollingChecksum {
+    fn new(window: &[u8]) -> Self {
+        let sum = adler32_slice(window) as u64;
+        RollingChecksum {
+            a: sum & 0xffff,
+            b: sum >> 16,
+            len: window.len() as u64,
+        }
+    }
+
+    //move the window one byte forward, out leaves it and new enters it
+    fn roll(&mut self, out: u8, new: u8) {
+        let (out, new) = (out as u64, new as u64);
+        self.a = (self.a + ADLER_MOD - out + new) % ADLER_MOD;
+        let removed = self.len % ADLER_MOD * out % ADLER_MOD;
+        self.b = (self.b + ADLER_MOD - removed + self.a + ADLER_MOD - 1) % ADLER_MOD;
+    }
+
+    fn sum(&self) -> u32 {
+        (self.b << 16 | self.a) as u32
+    }
+}
+
+//extend the last range when the new one follows it
+fn push_range(ranges: &mut Vec<Range<u64>>, range: Range<u64>) {
+    if range.is_empty() {
+        return;
+    }
+    match ranges.last_mut() {
+        Some(last) if last.end == range.start => last.end = range.end,
+        _ => ranges.push(range),
+    }
+}
+
+fn push_moved(moved: &mut Vec<Moved>, from: u64, to: Range<u64>) {
+    match moved.last_mut() {
+        Some(last) if last.to.end == to.start && last.from + (to.start - last.to.start) == from => {
+            last.to.end = to.end
+        }
+        _ => moved.push(Moved { from, to }),
+    }
+}
+
+//fill buf as far as the reader allows, returns the number of bytes read
+fn read_block<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+///builds a signature from data fed in chunks of any size,
+///so it can be computed while a file streams through
+pub struct SignatureBuilder {
+    block_size: u32,
+    pending: Vec<u8>,
+    blocks: Vec<BlockSignature>,
+    file_len: u64,
+    hasher: blake3::Hasher,
+}
+
+impl SignatureBuilder {
+    pub fn new(block_size: u32) -> Self {
+        SignatureBuilder {
+            block_size,
+            pending: Vec::with_capacity(block_size as usize),
+            blocks: vec![],
+            file_len: 0,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.file_len += data.len() as u64;
+        self.hasher.update(data);
+        let block_size = self.block_size as usize;
+        while !data.is_empty() {
+            let take = (block_size - self.pending.len()).min(data.len());
+            //hash whole blocks straight from the input without copying them
+            if self.pending.is_empty() && take == block_size {
+                self.blocks.push(block_signature(&data[..take]));
+            } else {
+                self.pending.extend_from_slice(&data[..take]);
+                if self.pending.len() == block_size {
+                    self.blocks.push(block_signature(&self.pending));
+                    self.pending.clear();
+                }
+            }
+            data = &data[take..];
+        }
+    }
+
+    pub fn finish(mut self) -> Signature {
+        if !self.pending.is_empty() {
+            self.blocks.push(block_signature(&self.pending));
+        }
+        Signature {
+            block_size: self.block_size,
+            file_len: self.file_len,
+            file_hash: *self.hasher.finalize().as_bytes(),
+            remote_stamp: None,
+            blocks: self.blocks,
+        }
+    }
+}
+
+impl Signature {
+    ///compare the new version of a file against this signature
+    pub fn diff<R: Read>(&self, reader: &mut R) -> Result<Delta, Error> {
+        let block_size = self.block_size as usize;
+        //whole blocks of the remote copy by weak checksum, the short last one is only
+        //looked for at the end of the new version
+        let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (index, block) in self.blocks.iter().enumerate() {
+            if self.block_len(index) == block_size as u64 {
+                by_weak.entry(block.weak).or_default().push(index);
+            }
+        }
+        let mut builder = SignatureBuilder::new(self.block_size);
+        let mut changed = vec![];
+        let mut moved = vec![];
+        //bytes of the new version from offset base on, only what the window still needs is kept
+        let mut buf = vec![];
+        let mut base = 0;
+        let mut eof = false;
+        //start of the window and of the bytes that matched no block yet
+        let mut pos = 0;
+        let mut unmatched = 0;
+        let mut rolling: Option<RollingChecksum> = None;
+        loop {
+            let start = (pos - base) as usize;
+            //the window and the byte after it, to roll into
+            if !eof && buf.len() <= start + block_size {
+                buf.drain(..start);
+                base = pos;
+                let filled = buf.len();
+                buf.resize(filled + READ_SIZE, 0);
+                let n = read_block(reader, &mut buf[filled..])?;
+                builder.update(&buf[filled..filled + n]);
+                buf.truncate(filled + n);
+                eof = n < READ_SIZE;
+                continue;
+            }
+            if buf.len() < start + block_size {
+                break;
+            }
+            let window = &buf[start..start + block_size];
+            let sum = rolling.get_or_insert_with(|| RollingChecksum::new(window));
+            if let Some(index) = self.find_block(&by_weak, sum.sum(), window, pos) {
+                push_range(&mut changed, unmatched..pos);
+                let from = index as u64 * block_size as u64;
+                let end = pos + block_size as u64;
+                if from != pos {
+                    push_moved(&mut moved, from, pos..end);
+                }
+                pos = end;
+                unmatched = end;
+                rolling = None;
+                continue;
+            }
+            match buf.get(start + block_size) {
+                Some(&new) => sum.roll(window[0], new),
+                None => break,
+            }
+            pos += 1;
+        }
+
+        //the end of the new version may be the short last block of the remote copy
+        let end = base + buf.len() as u64;
+        let tail = &buf[(pos - base) as usize..];
+        let last = self.blocks.len().checked_sub(1);
+        let tail_matches = match last {
+            Some(last) if !tail.is_empty() && self.block_len(last) == tail.len() as u64 => {
+                let old = &self.blocks[last];
+                old.weak == adler32_slice(tail) && old.strong == *blake3::hash(tail).as_bytes()
+            }
+            _ => false,
+        };
+        match (last, tail_matches) {
+            (Some(last), true) => {
+                push_range(&mut changed, unmatched..pos);
+                let from = last as u64 * block_size as u64;
+                if from != pos {
+                    push_moved(&mut moved, from, pos..end);
+                }
+            }
+            _ => push_range(&mut changed, unmatched..end),
+        }
+        Ok(Delta {
+            signature: builder.finish(),
+            changed,
+            moved,
+        })
+    }
+
+    //block of the remote copy holding window, one at the same offset is preferred
+    fn find_block(
+        &self,
+        by_weak: &HashMap<u32, Vec<usize>>,
+        weak: u32,
+        window: &[u8],
+        offset: u64,
+    ) -> Option<usize> {
+        let candidates = by_weak.get(&weak)?;
+        //the strong hash is only computed when the cheap one matches
+        let strong = *blake3::hash(window).as_bytes();
+        let mut found = None;
+        for index in candidates.iter().copied() {
+            if self.blocks[index].strong != strong {
+                continue;
+            }
+            if index as u64 * self.block_size as u64 == offset {
+                return Some(index);
+            }
+            found = found.or(Some(index));
+        }
+        found
+    }
+
+    fn block_len(&self, index: usize) -> u64 {
+        let start = index as u64 * self.block_size as u64;
+        (self.file_len - start).min(self.block_size as u64)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(69 + self.blocks.len() * 36);
+        out.extend_from_slice(SIGNATURE_MAGIC);
+        out.push(SIGNATURE_VERSION);
+        out.extend_from_slice(&self.block_size.to_le_bytes());
+        out.extend_from_slice(&self.file_len.to_le_bytes());
+        out.extend_from_slice(&(self.blocks.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.file_hash);
+        //no stamp is written as zeros
+        for value in self.remote_stamp.unwrap_or([0, 0]) {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        for block in &self.blocks {
+            out.extend_from_slice(&block.weak.to_le_bytes());
+            out.extend_from_slice(&block.strong);
+        }
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "invalid block signature");
+        if data.len() < 69 || &data[..4] != SIGNATURE_MAGIC || data[4] != SIGNATURE_VERSION {
+            return Err(invalid());
+        }
+        let block_size = u32::from_le_bytes(data[5..9].try_into().unwrap());
+        let file_len = u64::from_le_bytes(data[9..17].try_into().unwrap());
+        let count = u32::from_le_bytes(data[17..21].try_into().unwrap()) as usize;
+        let file_hash = data[21..53].try_into().unwrap();
+        let size = u64::from_le_bytes(data[53..61].try_into().unwrap());
+        let mtime = u64::from_le_bytes(data[61..69].try_into().unwrap());
+        let remote_stamp = Some([size, mtime]).filter(|stamp| stamp != &[0, 0]);
+        let body = &data[69..];
+        if block_size == 0 || body.len() != count * 36 {
+            return Err(invalid());
+        }
+        let blocks = body
+            .chunks_exact(36)
+            .map(|chunk| BlockSignature {
+                weak: u32::from_le_bytes(chunk[..4].try_into().unwrap()),
+                strong: chunk[4..].try_into().unwrap(),
+            })
+            .collect();
+        Ok(Signature {
+            block_size,
+            file_len,
+            file_hash,
+            remote_stamp,
+            blocks,
+        })
+    }
+}
+
+//folder of the state dir holding the signatures of a target
+pub const SIGNATURES_DIR: &str = "signatures";
+
+//signatures are stored under the state dir, named after the hash of the synced path
+fn signature_path(manifest: Manifest, item: &Path) -> PathBuf {
+    let name = blake3::hash(item.to_str().unwrap().as_bytes()).to_hex();
+    manifest
+        .state_path(SIGNATURES_DIR)
+        .join(format!("{}.sig", name))
+}
+
+///signature of the copy last uploaded for item, if one was recorded
+pub fn read_signature(manifest: Manifest, item: &Path) -> Option<Signature> {
+    let data = fs::read(signature_path(manifest, item)).ok()?;
+    Signature::from_bytes(&data).ok()
+}
+
+pub fn write_signature(
+    manifest: Manifest,
+    item: &Path,
+    signature: &Signature,
+) -> Result<(), crate::error::Error> {
+    let path = signature_path(manifest, item);
+    let parent = path.parent().unwrap();
+    fs::create_dir_all(parent).at(parent)?;
+    fs::write(&path, signature.to_bytes()).at(&path)
+}
+
+pub fn remove_signature(manifest: Manifest, item: &Path) -> Result<(), crate::error::Error> {
+    let path = signature_path(manifest, item);
+    match fs::remove_file(&path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err).at(&path),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    //xorshift bytes with no repeating pattern, so a block only matches where it came from
+    fn sample(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn signature_of(data: &[u8], block_size: u32) -> Signature {
+        let mut builder = SignatureBuilder::new(block_size);
+        builder.update(data);
+        builder.finish()
+    }
+
+    #[test]
+    fn test_block_size_for() {
+        assert_eq!(block_size_for(0), 4096);
+        assert_eq!(block_size_for(100 * 1024 * 1024), 10240);
+        assert_eq!(block_size_for(u64::MAX / 2), 128 * 1024);
+    }
+
+    #[test]
+    fn test_diff_unchanged_file() {
+        let data = sample(10_000);
+        let sig = signature_of(&data, 4096);
+        assert_eq!(sig.blocks.len(), 3);
+        let delta = sig.diff(&mut Cursor::new(&data)).unwrap();
+        assert!(delta.changed.is_empty());
+        assert!(delta.moved.is_empty());
+        assert_eq!(delta.signature, sig);
+    }
+
+    #[test]
+    fn test_diff_changed_blocks() {
+        let data = sample(20_000);
+        let sig = signature_of(&data, 4096);
+
+        let mut modified = data.clone();
+        modified[5000] ^= 0xff;
+        modified.extend_from_slice(b"appended");
+        let delta = sig.diff(&mut Cursor::new(&modified)).unwrap();
+        //second block and the grown last block
+        assert_eq!(delta.changed, vec![4096..8192, 16384..20008]);
+        assert_eq!(delta.signature.file_len, 20008);
+
+        let truncated = &data[..8192];
+        let delta = sig.diff(&mut Cursor::new(truncated)).unwrap();
+        assert!(delta.changed.is_empty());
+        assert_eq!(delta.signature.file_len, 8192);
+    }
+
+    #[test]
+    fn test_rolling_checksum() {
+        let data = sample(5000);
+        let mut rolling = RollingChecksum::new(&data[..4096]);
+        for start in 1..=data.len() - 4096 {
+            rolling.roll(data[start - 1], data[start + 4095]);
+            assert_eq!(rolling.sum(), adler32_slice(&data[start..start + 4096]));
+        }
+    }
+
+    #[test]
+    fn test_diff_shifted_blocks() {
+        let data = sample(20_000);
+        let sig = signature_of(&data, 4096);
+
+        //every block moves behind the inserted bytes, the short last one included
+        let mut inserted = b"inserted".to_vec();
+        inserted.extend_from_slice(&data);
+        let delta = sig.diff(&mut Cursor::new(&inserted)).unwrap();
+        assert_eq!(delta.changed, vec![0..8]);
+        assert_eq!(
+            delta.moved,
+            vec![Moved {
+                from: 0,
+                to: 8..20008
+            }]
+        );
+        assert_eq!(delta.signature, signature_of(&inserted, 4096));
+
+        //blocks after the removed bytes move back, those before stay in place
+        let mut removed = data[..4096].to_vec();
+        removed.extend_from_slice(&data[4100..]);
+        let delta = sig.diff(&mut Cursor::new(&removed)).unwrap();
+        assert_eq!(delta.changed, vec![4096..8188]);
+        assert_eq!(
+            delta.moved,
+            vec![Moved {
+                from: 8192,
+                to: 8188..19996
+            }]
+        );
+
+        //the window slides on across the reads of a larger file
+        let data = sample(600_000);
+        let sig = signature_of(&data, 4096);
+        let mut inserted = data[..300_001].to_vec();
+        inserted.push(7);
+        inserted.extend_from_slice(&data[300_001..]);
+        let delta = sig.diff(&mut Cursor::new(&inserted)).unwrap();
+        assert_eq!(delta.changed, vec![299_008..303_105]);
+        assert_eq!(
+            delta.moved,
+            vec![Moved {
+                from: 303_104,
+                to: 303_105..600_001
+            }]
+        );
+    }
+
+    #[test]
+    fn test_signature_builder_chunks() {
+        let data = sample(13_000);
+        let mut builder = SignatureBuilder::new(4096);
+        for chunk in data.chunks(1000) {
+            builder.update(chunk);
+        }
+        let sig = builder.finish();
+        assert_eq!(sig.blocks.len(), 4);
+        assert_eq!(sig.file_len, 13_000);
+        assert_eq!(sig, signature_of(&data, 4096));
+        assert_eq!(sig.blocks[3].weak, adler32_slice(&data[12288..]));
+    }
+
+    #[test]
+    fn test_signature_bytes_round_trip() {
+        let mut sig = signature_of(&sample(9000), 4096);
+        let parsed = Signature::from_bytes(&sig.to_bytes()).unwrap();
+        assert_eq!(parsed, sig);
+        sig.remote_stamp = Some([9000, 1_700_000_000]);
+        let parsed = Signature::from_bytes(&sig.to_bytes()).unwrap();
+        assert_eq!(parsed, sig);
+        assert!(Signature::from_bytes(b"XSIG").is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+///Errors returned by rxsync
+#[derive(Debug)]
+pub enum Error {
+    ///could not reach the server or complete the ssh handshake
+    Connection { addr: String, source: io::Error },
+    ///the server rejected the credentials for this user
+    Authentication { user: String, source: ssh2::Error },
+    ///the server failed an sftp or scp request on this remote path
+    Sftp { path: PathBuf, source: ssh2::Error },
+    ///streaming file content to or from the server failed
+    Transfer { path: PathBuf, source: io::Error },
+    ///reading or writing a local file failed
+    Io { path: PathBuf, source: io::Error },
+    ///the `.xsync.toml` file at this path could not be parsed or written
+    Manifest { path: PathBuf, message: String },
+    ///the `.xsync/config.toml` file at this path could not be parsed, or a remote from it can't be used
+    Config { path: PathBuf, message: String },
+    ///the `.xsyncignore` file at this path could not be read
+    Ignore { path: PathBuf, source: io::Error },
+    ///files changed both locally and on the server since the last sync
+    Conflict { paths: Vec<PathBuf> },
+    ///another run is syncing the folder whose lock file is at this path
+    Locked { path: PathBuf },
+}
+
+impl Error {
+    ///path of the local or remote item the error is about, if any
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::Connection { .. } | Error::Authentication { .. } => None,
+            Error::Sftp { path, .. }
+            | Error::Transfer { path, .. }
+            | Error::Io { path, .. }
+            | Error::Manifest { path, .. }
+            | Error::Config { path, .. }
+            | Error::Ignore { path, .. }
+            | Error::Locked { path } => Some(path),
+            Error::Conflict { paths } => paths.first().map(PathBuf::as_path),
+        }
+    }
+
+    ///sftp status code sent by the server, e.g. 2 for no such file
+    pub fn sftp_code(&self) -> Option<i32> {
+        match self {
+            Error::Sftp { source, .. } => match source.code() {
+                ssh2::ErrorCode::SFTP(code) => Some(code),
+                ssh2::ErrorCode::Session(_) => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Connection { addr, source } => {
+                write!(f, "could not connect to {}: {}", addr, source)
+            }
+            Error::Authentication { user, source } => {
+                write!(f, "authentication failed for {}: {}", user, source)
+            }
+            Error::Sftp { path, source } => write!(f, "remote error on {:?}: {}", path, source),
+            Error::Transfer { path, source } => {
+                write!(f, "transfer of {:?} failed: {}", path, source)
+            }
+            Error::Io { path, source } => write!(f, "io error on {:?}: {}", path, source),
+            Error::Manifest { path, message } => {
+                write!(f, "invalid config {:?}: {}", path, message)
+            }
+            Error::Config { path, message } => {
+                write!(f, "invalid remotes config {:?}: {}", path, message)
+            }
+            Error::Ignore { path, source } => {
+                write!(f, "could not read ignore file {:?}: {}", path, source)
+            }
+            Error::Conflict { paths } => {
+                write!(f, "changed both locally and on the server: {:?}", paths)
+            }
+            Error::Locked { path } => {
+                write!(f, "another sync is running, {:?} is locked", path)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Connection { source, .. }
+            | Error::Transfer { source, .. }
+            | Error::Io { source, .. }
+            | Error::Ignore { source, .. } => Some(source),
+            Error::Authentication { source, .. } | Error::Sftp { source, .. } => Some(source),
+            Error::Manifest { .. }
+            | Error::Config { .. }
+            | Error::Conflict { .. }
+            | Error::Locked { .. } => None,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+//attach the path an operation was working on to lower level errors
+pub(crate) trait PathContext<T> {
+    fn at(self, path: &Path) -> Result<T>;
+}
+
+impl<T> PathContext<T> for std::result::Result<T, io::Error> {
+    fn at(self, path: &Path) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+impl<T> PathContext<T> for std::result::Result<T, ssh2::Error> {
+    fn at(self, path: &Path) -> Result<T> {
+        self.map_err(|source| Error::Sftp {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
@@ -0,0 +1,150 @@
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::error::Error;
+
+///Something that happened while syncing or cloning
+#[derive(Debug)]
+pub enum SyncEvent<'a> {
+    ///a file started uploading or downloading
+    FileStarted { path: &'a Path, size: u64 },
+    ///bytes of the file sent or received so far
+    Progress { path: &'a Path, bytes: u64 },
+    ///a file was transferred, bytes is what actually went over the wire
+    FileFinished { path: &'a Path, bytes: u64 },
+    ///a file was left alone because it matches the last synced version
+    FileSkipped { path: &'a Path },
+    ///a symbolic link was left out by the policy or because it leads outside the synced folder
+    LinkSkipped { path: &'a Path },
+    ///the server lacks a command the faster way of sending a file needs, reason says
+    ///what was done instead
+    Fallback { path: &'a Path, reason: &'a str },
+    ///a folder was created
+    FolderCreated { path: &'a Path },
+    ///a file or folder was deleted
+    Deleted { path: &'a Path },
+    ///the run stopped on this error, it is also returned to the caller
+    Error { error: &'a Error },
+}
+
+///Receives events as a sync or clone runs
+///
+///Uploads run on several threads so handlers must be `Send + Sync`.
+///Any `Fn(&SyncEvent)` closure can be used as a handler
+pub trait EventHandler: Send + Sync {
+    fn handle(&self, event: &SyncEvent);
+}
+
+impl<F> EventHandler for F
+where
+    F: Fn(&SyncEvent) + Send + Sync,
+{
+    fn handle(&self, event: &SyncEvent) {
+        self(event)
+    }
+}
+
+///Prints a line to stdout for every event except progress
+pub struct PrintEvents;
+
+impl EventHandler for PrintEvents {
+    fn handle(&self, event: &SyncEvent) {
+        match event {
+            SyncEvent::FileStarted { path, size } => {
+                println!("sending file {:?} of size {}", path, size)
+            }
+            SyncEvent::Progress { .. } => {}
+            SyncEvent::FileFinished { path, bytes } => {
+                println!("done with file {:?}, {} bytes transferred", path, bytes)
+            }
+            SyncEvent::FileSkipped { path } => {
+                println!("no update made to file {:?}. Nothing new to update", path)
+            }
+            SyncEvent::LinkSkipped { path } => println!("skipped link {:?}", path),
+            SyncEvent::Fallback { path, reason } => println!("{:?}: {}", path, reason),
+            SyncEvent::FolderCreated { path } => println!("created folder {:?}", path),
+            SyncEvent::Deleted { path } => println!("deleted {:?}", path),
+            SyncEvent::Error { error } => eprintln!("{}", error),
+        }
+    }
+}
+
+//the handler set in the options, if any
+#[derive(Clone, Copy)]
+pub(crate) struct Events<'a>(pub Option<&'a dyn EventHandler>);
+
+impl Events<'_> {
+    pub fn emit(&self, event: SyncEvent) {
+        if let Some(handler) = self.0 {
+            handler.handle(&event);
+        }
+    }
+
+    //report an error before handing it back to the caller
+    pub fn check<T>(&self, result: Result<T, Error>) -> Result<T, Error> {
+        if let Err(error) = &result {
+            self.emit(SyncEvent::Error { error });
+        }
+        result
+    }
+}
+
+//reader that reports how many bytes went through it
+pub(crate) struct ProgressReader<'a, R> {
+    inner: R,
+    path: &'a Path,
+    bytes: u64,
+    events: Events<'a>,
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    //start is the number of bytes of the file already transferred
+    pub fn new(inner: R, path: &'a Path, start: u64, events: Events<'a>) -> Self {
+        ProgressReader {
+            inner,
+            path,
+            bytes: start,
+            events,
+        }
+    }
+
+    pub fn transferred(&self) -> u64 {
+        self.bytes
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.bytes += n as u64;
+            self.events.emit(SyncEvent::Progress {
+                path: self.path,
+                bytes: self.bytes,
+            });
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_progress_reader_reports_bytes() {
+        let seen = Mutex::new(vec![]);
+        let handler = |event: &SyncEvent| {
+            if let SyncEvent::Progress { bytes, .. } = event {
+                seen.lock().unwrap().push(*bytes);
+            }
+        };
+        let data = [1u8; 10];
+        let mut reader =
+            ProgressReader::new(&data[..], Path::new("file"), 0, Events(Some(&handler)));
+        let mut buf = [0u8; 4];
+        while reader.read(&mut buf).unwrap() > 0 {}
+        assert_eq!(*seen.lock().unwrap(), vec![4, 8, 10]);
+    }
+}
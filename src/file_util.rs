@@ -1,138 +1,646 @@
-use crate::config::{CHECKSUM_FILE, IGNORE_FILE};
-use adler::adler32_slice;
-use glob::glob;
+use crate::config::{
+    Config, IgnoreRules, Stamp, CHECKSUM_FILE, IGNORE_FILE, STATE_DIR, TEMP_SUFFIX,
+};
+use crate::delta::{Signature, SignatureBuilder};
+use crate::error::{Error, PathContext};
+use crate::hash::Hasher;
+use crate::options::{HashAlgorithm, Preserve, SymlinkPolicy, SyncOptions};
+use ssh2::FileStat;
+use std::collections::HashSet;
 use std::fs;
-use std::io::Error;
-use std::path::{Path, PathBuf};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+///size of the chunks files are streamed in
+pub const CHUNK_SIZE: usize = 64 * 1024;
 
 pub fn get_file_size(path: &Path) -> Result<u64, Error> {
-    let metadata = fs::metadata(path)?;
+    let metadata = fs::metadata(path).at(path)?;
     Ok(metadata.len())
 }
 
-pub fn check_if_file(path: &Path) -> Result<bool, Error> {
-    let metadata = fs::metadata(path)?;
-    Ok(metadata.is_file())
-}
-
 pub fn check_if_dir(path: &Path) -> Result<bool, Error> {
-    let metadata = fs::metadata(path)?;
+    let metadata = fs::metadata(path).at(path)?;
     Ok(metadata.is_dir())
 }
 
-pub fn create_checksum(buf: &[u8]) -> u32 {
-    adler32_slice(buf)
+//files kept by rxsync itself under the state dir, and uploads not yet moved
+//over their target, are never synced
+pub fn is_state_path(path: &Path) -> bool {
+    let temp = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('.') && name.ends_with(TEMP_SUFFIX));
+    temp || path
+        .components()
+        .any(|component| component.as_os_str() == STATE_DIR)
+}
+
+///files and folders under a local folder, with symbolic links sorted out by the policy
+#[derive(Debug, Default)]
+pub struct LocalTree {
+    pub files: Vec<PathBuf>,
+    pub folders: Vec<PathBuf>,
+    ///links to recreate as links, with the target to give them
+    pub links: Vec<(PathBuf, PathBuf)>,
+    ///links left out by the policy, leading outside the folder or looping back into it
+    pub skipped_links: Vec<PathBuf>,
 }
 
-pub fn get_all_files_subdir(path: &str) -> Result<Vec<PathBuf>, Error> {
-    let resolved_path = format!("{}/**/*", path);
-    let mut file_paths = vec![];
-    for entry in glob(&resolved_path).expect("Failed to read glob pattern") {
-        match entry {
-            Ok(paths) => {
-                let check = check_if_file(Path::new(&paths))?;
-                //ignore folders amd config files
-                if check
-                    && !Path::new(&paths).ends_with(IGNORE_FILE)
-                    && !Path::new(&paths).ends_with(CHECKSUM_FILE)
-                {
-                    file_paths.push(paths);
+pub fn list_local(path: &Path, policy: SymlinkPolicy) -> Result<LocalTree, Error> {
+    let root = fs::canonicalize(path).at(path)?;
+    let mut tree = LocalTree::default();
+    //every folder is only followed into once so links can't loop
+    let mut visited = HashSet::from([root.clone()]);
+    //keys in the toml config are written without a leading `./`
+    let start: PathBuf = path
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect();
+    let mut pending = vec![start];
+    while let Some(dir) = pending.pop() {
+        let read_path = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir.as_path()
+        };
+        for entry in fs::read_dir(read_path).at(read_path)? {
+            let item = dir.join(entry.at(read_path)?.file_name());
+            if is_state_path(&item) || item.ends_with(CHECKSUM_FILE) || item.ends_with(IGNORE_FILE)
+            {
+                continue;
+            }
+            let file_type = fs::symlink_metadata(&item).at(&item)?.file_type();
+            if file_type.is_dir() {
+                tree.folders.push(item.clone());
+                pending.push(item);
+            } else if file_type.is_file() {
+                tree.files.push(item);
+            } else if file_type.is_symlink() {
+                match policy {
+                    SymlinkPolicy::Skip => tree.skipped_links.push(item),
+                    SymlinkPolicy::Recreate => match local_link_target(&item, &root)? {
+                        Some(target) => tree.links.push((item, target)),
+                        None => tree.skipped_links.push(item),
+                    },
+                    SymlinkPolicy::Follow => match fs::canonicalize(&item) {
+                        Ok(target) if target.starts_with(&root) && target.is_dir() => {
+                            if visited.insert(target) {
+                                tree.folders.push(item.clone());
+                                pending.push(item);
+                            } else {
+                                tree.skipped_links.push(item);
+                            }
+                        }
+                        Ok(target) if target.starts_with(&root) => tree.files.push(item),
+                        //broken or leading outside root
+                        _ => tree.skipped_links.push(item),
+                    },
                 }
             }
-            Err(_) => {}
         }
     }
-    Ok(file_paths)
+    tree.files.sort();
+    tree.folders.sort();
+    tree.links.sort();
+    tree.skipped_links.sort();
+    Ok(tree)
 }
 
-pub fn get_all_subdir(path: &str) -> Result<Vec<PathBuf>, Error> {
-    let resolved_path = format!("{}/**", path);
-    let mut folder_paths = vec![];
-    for entry in glob(&resolved_path).expect("Failed to read glob pattern") {
-        match entry {
-            Ok(path) => folder_paths.push(path),
-            Err(_) => {}
+//resolve `.` and `..` without touching the file system
+pub fn normalize_lexically(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
         }
     }
-    Ok(folder_paths)
+    normalized
 }
 
-pub fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
-    let data = fs::read(path)?;
-    Ok(data)
+//relative path leading from the folder from to path, both absolute and normalized
+fn relative_to(from: &Path, path: &Path) -> PathBuf {
+    let common = from
+        .components()
+        .zip(path.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut relative = PathBuf::new();
+    for _ in from.components().skip(common) {
+        relative.push("..");
+    }
+    for component in path.components().skip(common) {
+        relative.push(component);
+    }
+    relative
 }
 
-pub fn remove_ignored_path(
-    src_path: &Path,
-    src: &mut Vec<PathBuf>,
-    ignore: &Vec<String>,
-) -> Vec<PathBuf> {
-    let folder_path = format!("{}/{}", src_path.to_str().unwrap(), IGNORE_FILE);
-    if Path::new(&folder_path).exists() {
-        for i in 0..src.len() {
-            for j in ignore {
-                if src[i].starts_with(j) {
-                    src[i] = PathBuf::from("");
-                }
-            }
+//target to give a recreated link whose folder is parent, None if it leads outside root.
+//parent and root are absolute, absolute targets are made relative so they still hold
+//once the tree is copied somewhere else
+pub fn contained_link_target(parent: &Path, target: &Path, root: &Path) -> Option<PathBuf> {
+    let resolved = normalize_lexically(&parent.join(target));
+    if !resolved.starts_with(root) {
+        return None;
+    }
+    if target.is_relative() {
+        Some(target.to_path_buf())
+    } else {
+        Some(relative_to(parent, &resolved))
+    }
+}
+
+//target of a local link to recreate, None if it leads outside root, the canonical synced folder
+pub fn local_link_target(link: &Path, root: &Path) -> Result<Option<PathBuf>, Error> {
+    let target = fs::read_link(link).at(link)?;
+    let parent = link.parent().unwrap_or_else(|| Path::new("."));
+    let parent = fs::canonicalize(parent).at(parent)?;
+    //links inside the target path can still lead out
+    if let Ok(real) = fs::canonicalize(link) {
+        if !real.starts_with(root) {
+            return Ok(None);
         }
+    }
+    Ok(contained_link_target(&parent, &target, root))
+}
+
+//replace whatever is at path with a link to target
+//returns false where links can't be created
+#[cfg(unix)]
+pub fn create_local_link(target: &Path, path: &Path) -> Result<bool, Error> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path).at(path)?,
+        Ok(_) => fs::remove_file(path).at(path)?,
+        Err(_) => {}
+    }
+    std::os::unix::fs::symlink(target, path).at(path)?;
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+pub fn create_local_link(_target: &Path, _path: &Path) -> Result<bool, Error> {
+    Ok(false)
+}
+
+//checksum of a file read in fixed size chunks, as recorded in the toml config
+pub fn checksum_file(path: &Path, hash: HashAlgorithm) -> Result<String, Error> {
+    let mut reader = ChecksumReader::new(fs::File::open(path).at(path)?, hash);
+    copy_chunked(&mut reader, &mut io::sink()).at(path)?;
+    Ok(reader.checksum())
+}
+
+//whether a file still matches the value recorded for it in the toml config,
+//hashed with the algorithm the value was written with
+pub fn matches_recorded(path: &Path, recorded: &str) -> Result<bool, Error> {
+    match HashAlgorithm::of(recorded) {
+        Some(hash) => Ok(checksum_file(path, hash)? == recorded),
+        None => Ok(false),
+    }
+}
+
+//how a local file compares with its entry in the toml config
+#[derive(Debug, PartialEq, Eq)]
+pub enum Recorded {
+    Changed,
+    Unchanged,
+    //unchanged but the entry is stale, with the value and stamp to record instead
+    Refresh(String, Option<Stamp>),
+}
+
+//compare a local file with the value recorded for it under key
+//the file is only read when its stamp moved since the last sync or options.checksum is set
+pub fn compare_recorded(
+    path: &Path,
+    key: &str,
+    config: &Config,
+    options: &SyncOptions,
+) -> Result<Recorded, Error> {
+    let (recorded, recorded_stamp) = match config.files.get(key) {
+        Some(entry) => (&entry.checksum, entry.stamp),
+        None => return Ok(Recorded::Changed),
+    };
+    let stamp = local_stamp(path)?;
+    let same_stamp = stamp.is_some() && recorded_stamp == stamp;
+    if same_stamp && !options.checksum {
+        return Ok(Recorded::Unchanged);
+    }
+    if !matches_recorded(path, recorded)? {
+        return Ok(Recorded::Changed);
+    }
+    //entries written with another hash are moved over without sending the file again
+    if HashAlgorithm::of(recorded) != Some(options.hash) {
+        return Ok(Recorded::Refresh(checksum_file(path, options.hash)?, stamp));
+    }
+    if !same_stamp {
+        return Ok(Recorded::Refresh(recorded.clone(), stamp));
+    }
+    Ok(Recorded::Unchanged)
+}
+
+//copy from reader to writer through a single fixed size buffer
+pub fn copy_chunked<R: Read + ?Sized, W: io::Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+) -> io::Result<u64> {
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut total = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(total),
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        writer.write_all(&buf[..n])?;
+        total += n as u64;
+    }
+}
+
+//copy reader to writer once past its first skip bytes, which the writer already has
+pub fn copy_after<R: Read + ?Sized, W: io::Write + ?Sized>(
+    reader: &mut R,
+    skip: u64,
+    writer: &mut W,
+) -> io::Result<u64> {
+    copy_chunked(&mut reader.take(skip), &mut io::sink())?;
+    copy_chunked(reader, writer)
+}
+
+//checksum of the first len bytes of a local file
+pub fn prefix_checksum(path: &Path, len: u64, hash: HashAlgorithm) -> Result<String, Error> {
+    let file = fs::File::open(path).at(path)?;
+    let mut reader = ChecksumReader::new(file.take(len), hash);
+    copy_chunked(&mut reader, &mut io::sink()).at(path)?;
+    Ok(reader.checksum())
+}
+
+///reader that computes the checksum, and optionally the block signature,
+///of the data passing through it
+pub struct ChecksumReader<R> {
+    inner: R,
+    hasher: Hasher,
+    signature: Option<SignatureBuilder>,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    pub fn new(inner: R, hash: HashAlgorithm) -> Self {
+        ChecksumReader {
+            inner,
+            hasher: Hasher::new(hash),
+            signature: None,
+        }
+    }
+
+    pub fn with_signature(inner: R, hash: HashAlgorithm, block_size: u32) -> Self {
+        ChecksumReader {
+            inner,
+            hasher: Hasher::new(hash),
+            signature: Some(SignatureBuilder::new(block_size)),
+        }
+    }
+
+    //value to record in the toml config
+    pub fn checksum(&self) -> String {
+        self.hasher.value()
+    }
+
+    pub fn into_signature(self) -> Option<Signature> {
+        self.signature.map(SignatureBuilder::finish)
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        if let Some(signature) = &mut self.signature {
+            signature.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+fn unix_secs(time: io::Result<SystemTime>) -> Option<u64> {
+    Some(time.ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+//stamp recorded for a local file so the next sync can skip reading it when it didn't move
+pub fn local_stamp(path: &Path) -> Result<Option<Stamp>, Error> {
+    let metadata = fs::metadata(path).at(path)?;
+    let modified = metadata.modified().ok();
+    let nanos = modified.and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+    Ok(nanos.map(|nanos| [metadata.len(), nanos.as_nanos() as u64]))
+}
+
+//metadata of a local item to set on its copy on the server, as selected by preserve
+pub fn local_stat(path: &Path, preserve: Preserve) -> Result<FileStat, Error> {
+    let metadata = fs::metadata(path).at(path)?;
+    let mut stat = FileStat {
+        size: None,
+        uid: None,
+        gid: None,
+        perm: None,
+        atime: None,
+        mtime: None,
+    };
+    if preserve.times {
+        stat.atime = unix_secs(metadata.accessed());
+        stat.mtime = unix_secs(metadata.modified());
+    }
+    if preserve.mode {
+        stat.perm = Some(mode_of(&metadata));
+    }
+    #[cfg(unix)]
+    if preserve.owner {
+        use std::os::unix::fs::MetadataExt;
+        stat.uid = Some(metadata.uid());
+        stat.gid = Some(metadata.gid());
+    }
+    Ok(stat)
+}
+
+//permission bits of a local item
+pub fn local_mode(path: &Path) -> Result<u32, Error> {
+    Ok(mode_of(&fs::metadata(path).at(path)?))
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+//no mode bits outside of unix, derive them from the read only flag
+#[cfg(not(unix))]
+fn mode_of(metadata: &fs::Metadata) -> u32 {
+    let mode = if metadata.is_dir() { 0o755 } else { 0o644 };
+    if metadata.permissions().readonly() {
+        mode & 0o555
     } else {
-        return src.to_vec();
+        mode
     }
+}
 
-    src.retain(|x| x.to_str().unwrap() != "");
-    src.to_vec()
+//set metadata read from the server on a local item, as selected by preserve
+//the mode goes last so a read only mode doesn't block the rest
+pub fn apply_stat(path: &Path, stat: &FileStat, preserve: Preserve) -> Result<(), Error> {
+    if preserve.times {
+        let to_time = |secs: u64| UNIX_EPOCH + Duration::from_secs(secs);
+        let mut times = fs::FileTimes::new();
+        if let Some(mtime) = stat.mtime {
+            times = times.set_modified(to_time(mtime));
+        }
+        if let Some(atime) = stat.atime {
+            times = times.set_accessed(to_time(atime));
+        }
+        fs::File::open(path).at(path)?.set_times(times).at(path)?;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let (true, Some(uid), Some(gid)) = (preserve.owner, stat.uid, stat.gid) {
+            std::os::unix::fs::chown(path, Some(uid), Some(gid)).at(path)?;
+        }
+        if let (true, Some(perm)) = (preserve.mode, stat.perm) {
+            fs::set_permissions(path, fs::Permissions::from_mode(perm & 0o7777)).at(path)?;
+        }
+    }
+    #[cfg(not(unix))]
+    if let (true, Some(perm)) = (preserve.mode, stat.perm) {
+        let mut permissions = fs::metadata(path).at(path)?.permissions();
+        permissions.set_readonly(perm & 0o222 == 0);
+        fs::set_permissions(path, permissions).at(path)?;
+    }
+    Ok(())
+}
+
+//drop every path matched by the ignore rules
+pub fn remove_ignored_path(src: &mut Vec<PathBuf>, ignore: &IgnoreRules) {
+    src.retain(|item| !ignore.is_ignored(item, item.is_dir()));
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::FileEntry;
+    use std::collections::HashMap;
     use std::fs::File;
     use std::io::prelude::*;
 
     #[test]
-    fn test_get_all_files_subdir() {
-        let file_list = get_all_files_subdir("test_sync").unwrap();
-        assert!(file_list.contains(&Path::new("test_sync/keep.txt").to_path_buf()));
-        assert!(file_list.contains(&Path::new("test_sync/test2/test3/test_file").to_path_buf()));
+    fn test_list_local() {
+        let tree = list_local(Path::new("./test_sync"), SymlinkPolicy::default()).unwrap();
+        assert!(tree
+            .files
+            .contains(&Path::new("test_sync/keep.txt").to_path_buf()));
+        assert!(tree
+            .files
+            .contains(&Path::new("test_sync/test2/test3/test_file").to_path_buf()));
+        assert!(tree
+            .folders
+            .contains(&Path::new("test_sync/test2").to_path_buf()));
+        assert!(tree
+            .folders
+            .contains(&Path::new("test_sync/test2/test3").to_path_buf()));
     }
 
     #[test]
-    fn test_get_all_subdir() {
-        let dir_list = get_all_subdir("test_sync").unwrap();
-        assert!(dir_list.contains(&Path::new("test_sync/test2").to_path_buf()));
-        assert!(dir_list.contains(&Path::new("test_sync/test2/test3").to_path_buf()));
+    fn test_is_state_path() {
+        assert!(is_state_path(Path::new("root/.xsync/signatures/file")));
+        assert!(is_state_path(Path::new("root/sub/.file.xsync-tmp")));
+        assert!(!is_state_path(Path::new("root/sub/file.xsync-tmp")));
+        assert!(!is_state_path(Path::new("root/sub/.file")));
+    }
+
+    #[test]
+    fn test_copy_after() {
+        let data = b"already sent, then the rest";
+        let mut reader = ChecksumReader::new(&data[..], HashAlgorithm::Blake3);
+        let mut written = vec![];
+        let bytes = copy_after(&mut reader, 13, &mut written).unwrap();
+        assert_eq!(bytes, 14);
+        assert_eq!(written, b" then the rest");
+        assert_eq!(
+            reader.checksum(),
+            HashAlgorithm::Blake3.value_of_hex(&blake3::hash(data).to_hex())
+        );
+    }
+
+    #[test]
+    fn test_checksum_reader() {
+        let data = vec![7u8; CHUNK_SIZE * 2 + 100];
+        let mut reader = ChecksumReader::with_signature(&data[..], HashAlgorithm::Adler32, 4096);
+        let mut out = vec![];
+        assert_eq!(
+            copy_chunked(&mut reader, &mut out).unwrap(),
+            data.len() as u64
+        );
+        assert_eq!(out, data);
+        assert_eq!(
+            reader.checksum(),
+            format!("{}", adler::adler32_slice(&data))
+        );
+        assert_eq!(reader.into_signature().unwrap().file_len, data.len() as u64);
+
+        let checksum =
+            checksum_file(Path::new("test_sync/keep.txt"), HashAlgorithm::Blake3).unwrap();
+        let content = fs::read("test_sync/keep.txt").unwrap();
+        assert_eq!(
+            checksum,
+            format!("blake3:{}", blake3::hash(&content).to_hex())
+        );
+    }
+
+    #[test]
+    fn test_local_stat_round_trip() {
+        let path = Path::new("test_local_stat");
+        fs::write(path, b"#!/bin/sh").unwrap();
+        let preserve = Preserve {
+            mode: true,
+            times: true,
+            owner: false,
+        };
+        let mut stat = local_stat(path, preserve).unwrap();
+        assert!(stat.uid.is_none());
+        stat.perm = Some(0o755);
+        stat.mtime = Some(1_000_000_000);
+        stat.atime = Some(1_000_000_000);
+        apply_stat(path, &stat, preserve).unwrap();
+        let copied = local_stat(path, preserve).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(copied.mtime, Some(1_000_000_000));
+        #[cfg(unix)]
+        assert_eq!(copied.perm, Some(0o755));
     }
 
     #[test]
     fn test_remove_ignored_path() {
-        let unignored_path = remove_ignored_path(
-            Path::new("test_sync"),
-            &mut vec![
-                Path::new("path1").to_path_buf(),
-                Path::new("path2").to_path_buf(),
-                Path::new("path3").to_path_buf(),
-            ],
-            &vec!["path2".to_string()],
+        let root = Path::new("test_remove_ignored");
+        fs::create_dir_all(root.join("logs")).unwrap();
+        let mut file = File::create(root.join(IGNORE_FILE)).unwrap();
+        file.write_all(b"logs/\n*.tmp").unwrap();
+        let ignore = crate::config::get_ignore_file(root, &[]).unwrap();
+        let mut paths = vec![
+            root.join("path1"),
+            root.join("logs"),
+            root.join("logs/today"),
+            root.join("path2.tmp"),
+        ];
+        remove_ignored_path(&mut paths, &ignore);
+        fs::remove_dir_all(root).unwrap();
+        assert_eq!(paths, vec![root.join("path1")]);
+
+        let mut paths = vec![root.join("path2.tmp")];
+        remove_ignored_path(&mut paths, &IgnoreRules::default());
+        assert_eq!(paths.len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_list_local_links() {
+        use std::os::unix::fs::symlink;
+        let root = Path::new("test_links");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub/file"), b"data").unwrap();
+        symlink("sub/file", root.join("to_file")).unwrap();
+        symlink("sub", root.join("to_sub")).unwrap();
+        symlink("..", root.join("sub/loop")).unwrap();
+        symlink("/etc", root.join("outside")).unwrap();
+
+        let follow = list_local(root, SymlinkPolicy::Follow).unwrap();
+        let recreate = list_local(root, SymlinkPolicy::Recreate).unwrap();
+        let skip = list_local(root, SymlinkPolicy::Skip).unwrap();
+        fs::remove_dir_all(root).unwrap();
+
+        assert!(follow.files.contains(&root.join("to_file")));
+        assert!(follow.files.contains(&root.join("to_sub/file")));
+        assert!(follow.folders.contains(&root.join("to_sub")));
+        assert!(follow.skipped_links.contains(&root.join("sub/loop")));
+        assert!(follow.skipped_links.contains(&root.join("outside")));
+        assert!(follow.links.is_empty());
+
+        assert!(recreate
+            .links
+            .contains(&(root.join("to_sub"), PathBuf::from("sub"))));
+        assert!(recreate
+            .links
+            .contains(&(root.join("sub/loop"), PathBuf::from(".."))));
+        assert_eq!(recreate.skipped_links, vec![root.join("outside")]);
+        assert_eq!(recreate.files, vec![root.join("sub/file")]);
+
+        assert_eq!(skip.skipped_links.len(), 4);
+        assert!(skip.links.is_empty());
+    }
+
+    #[test]
+    fn test_contained_link_target() {
+        let root = Path::new("/data/root");
+        let parent = Path::new("/data/root/a/b");
+        assert_eq!(
+            contained_link_target(parent, Path::new("../c"), root),
+            Some(PathBuf::from("../c"))
+        );
+        assert_eq!(
+            contained_link_target(parent, Path::new("/data/root/c/d"), root),
+            Some(PathBuf::from("../../c/d"))
+        );
+        assert_eq!(
+            contained_link_target(parent, Path::new("../../../x"), root),
+            None
         );
-        assert!(unignored_path.len() == 3);
-
-        let path = format!("test_sync/{}", IGNORE_FILE);
-        let mut file = File::create(&path).expect("Error encountered while creating file!");
-        file.write_all(b"path2")
-            .expect("Error while writing to file");
-        let unignored_path = remove_ignored_path(
-            Path::new("test_sync"),
-            &mut vec![
-                Path::new("path1").to_path_buf(),
-                Path::new("path2").to_path_buf(),
-                Path::new("path3").to_path_buf(),
-            ],
-            &vec!["path2".to_string()],
+        assert_eq!(
+            contained_link_target(parent, Path::new("/etc/passwd"), root),
+            None
         );
-        assert!(unignored_path.len() == 2);
-        assert!(unignored_path.contains(&Path::new("path1").to_path_buf()));
-        assert!(unignored_path.contains(&Path::new("path3").to_path_buf()));
-        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_compare_recorded() {
+        let path = Path::new("test_compare_recorded");
+        fs::write(path, b"first").unwrap();
+        let key = path.to_str().unwrap();
+        let blake3 = checksum_file(path, HashAlgorithm::Blake3).unwrap();
+        let mut config = Config {
+            folders: HashMap::new(),
+            files: HashMap::from([(key.to_string(), FileEntry::new(&blake3))]),
+            ..Default::default()
+        };
+        let stamp = local_stamp(path).unwrap();
+        let mut options = SyncOptions::default();
+        //no stamp yet, the file is read and the stamp recorded
+        assert_eq!(
+            compare_recorded(path, key, &config, &options).unwrap(),
+            Recorded::Refresh(blake3.clone(), stamp)
+        );
+
+        //same size and time, the content is not read unless checksum is set
+        config.files.get_mut(key).unwrap().stamp = stamp;
+        let modified = fs::metadata(path).unwrap().modified().unwrap();
+        fs::write(path, b"other").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let unchanged = compare_recorded(path, key, &config, &options).unwrap();
+        options.checksum = true;
+        let changed = compare_recorded(path, key, &config, &options).unwrap();
+
+        //an adler-32 entry moves to the configured hash
+        fs::write(path, b"first").unwrap();
+        let adler = checksum_file(path, HashAlgorithm::Adler32).unwrap();
+        config.files.insert(key.to_string(), FileEntry::new(&adler));
+        let migrated = compare_recorded(path, key, &config, &options).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(unchanged, Recorded::Unchanged);
+        assert_eq!(changed, Recorded::Changed);
+        assert!(matches!(migrated, Recorded::Refresh(checksum, _) if checksum == blake3));
     }
 }
@@ -0,0 +1,116 @@
+//! Content hashes recorded in `.xsync.toml`
+//!
+//! Each value starts with the name of its algorithm, like `blake3:<hex>`.
+//! Adler-32 values are written as a bare decimal number, as older versions did.
+
+use adler::Adler32;
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::options::HashAlgorithm;
+
+impl HashAlgorithm {
+    fn prefix(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Adler32 => "",
+            HashAlgorithm::Blake3 => "blake3:",
+            HashAlgorithm::Sha256 => "sha256:",
+            HashAlgorithm::Xxh3 => "xxh3:",
+        }
+    }
+
+    //command printing this hash of files on a server, in the `<hex>  <path>` format
+    pub fn command(&self) -> Option<&'static str> {
+        match self {
+            HashAlgorithm::Blake3 => Some("b3sum"),
+            HashAlgorithm::Sha256 => Some("sha256sum"),
+            HashAlgorithm::Adler32 | HashAlgorithm::Xxh3 => None,
+        }
+    }
+
+    //value recorded in the toml config for a hash printed in hex by command
+    pub fn value_of_hex(&self, hex: &str) -> String {
+        format!("{}{}", self.prefix(), hex)
+    }
+
+    ///algorithm a value of the toml config was written with, None for anything that isn't a hash
+    pub fn of(value: &str) -> Option<HashAlgorithm> {
+        [
+            HashAlgorithm::Blake3,
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Xxh3,
+        ]
+        .into_iter()
+        .find(|algorithm| value.starts_with(algorithm.prefix()))
+        .or_else(|| value.parse::<u32>().ok().map(|_| HashAlgorithm::Adler32))
+    }
+}
+
+//incremental state of any of the algorithms
+pub enum Hasher {
+    Adler32(Adler32),
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Sha256),
+    Xxh3(Box<Xxh3>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Adler32 => Hasher::Adler32(Adler32::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::default()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Xxh3 => Hasher::Xxh3(Box::default()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Adler32(hasher) => hasher.write_slice(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Xxh3(hasher) => hasher.update(data),
+        }
+    }
+
+    //value to record in the toml config
+    pub fn value(&self) -> String {
+        match self {
+            Hasher::Adler32(hasher) => format!("{}", hasher.checksum()),
+            Hasher::Blake3(hasher) => format!("blake3:{}", hasher.finalize().to_hex()),
+            Hasher::Sha256(hasher) => format!("sha256:{:x}", hasher.clone().finalize()),
+            Hasher::Xxh3(hasher) => format!("xxh3:{:016x}", hasher.digest()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_values() {
+        let data = b"same length";
+        for algorithm in [
+            HashAlgorithm::Adler32,
+            HashAlgorithm::Blake3,
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Xxh3,
+        ] {
+            let mut hasher = Hasher::new(algorithm);
+            hasher.update(&data[..4]);
+            hasher.update(&data[4..]);
+            let value = hasher.value();
+            assert_eq!(HashAlgorithm::of(&value), Some(algorithm));
+            let mut other = Hasher::new(algorithm);
+            other.update(b"same lengtH");
+            assert_ne!(other.value(), value);
+        }
+        let mut hasher = Hasher::new(HashAlgorithm::Adler32);
+        hasher.update(data);
+        assert_eq!(hasher.value(), format!("{}", adler::adler32_slice(data)));
+        assert_eq!(HashAlgorithm::of("link:target"), None);
+    }
+}
@@ -1,322 +1,619 @@
-//! A tool to help sync items in your local and remove server, pushing only modifications just like rsync
-//!
-//! - You can ignore files and folders by creating a `.xsyncignore` file in the base directory similar way you'd write a `.gitignore` file
-//!
-//!
-//!
-//! - To sync a file or directory to a remote server
-//!
-//! ```no_run
-//! use std:: path::Path;
-//! use rxsync::{connection::SshCred, sync, connection::AuthOption};
-//!
-//! //multiple auth options include
-//! //Attempt basic password authentication.
-//! let auth1= AuthOption::UserauthPassword("ssh_username".to_string(), "ssh_password".to_string());
-//! //authenticate the current connection with the first public key found in an SSH agent
-//! let auth2= AuthOption::UserauthAgent("ssh_username".to_string());
-//! //Attempt public key authentication using a PEM encoded private key file stored on disk
-//! let auth3= AuthOption::UserauthPubkeyFile("ssh_username".to_string(), Some(&Path::new("pub_key")), &Path::new("private_key"), Some("passphrase"));
-//!
-//! let conn =SshCred::new(
-//!     auth1,
-//!     "host".to_string(),
-//!     "port".to_string(),
-//!  );
-//!
-//! sync(&conn, &Path::new("source_path/"), Some(Path::new("dir_path"))).unwrap();
-//! ```
-//!
-//! - This creates a `.xsync.toml` file in the base directory which is a snapshot of the latest synced files and directories on the server
-//!   This file is how rxsync can track what files or dir to update, delete or upload
-//!
-//! - To clone a directory or file
-//!
-//! ```no_run
-//! use std:: path::Path;
-//! use rxsync::{connection::SshCred, clone_dir, clone_file, connection::AuthOption};
-//!
-//! let auth= AuthOption::UserauthAgent("ssh_username".to_string());
-//! let conn =SshCred::new(
-//!     auth,
-//!     "host".to_string(),
-//!     "port".to_string(),
-//!  );
-//!
-//! clone_dir(&conn, &Path::new("dir_to_clone"), &Path::new("write_dest")).unwrap();
-//!
-//! //config_dest is the destination you wish to write your .xsync.toml file which is optional
-//! clone_file(&conn, &Path::new("file_to_clone"), &Path::new("write_dest"), Some(&Path::new("config_dest"))).unwrap();
-//!
-//! ```
+#![doc = include_str!("../README.md")]
 
 use crate::config::*;
 use crate::connection::*;
+use crate::error::{Error, PathContext};
+use crate::events::*;
 use crate::file_util::*;
+use crate::options::*;
+use crate::plan::*;
+use crate::pool::*;
+use crate::report::*;
 use crate::sftp::*;
-use std::io::Error;
+use crate::transfer::{remote_stamp, Transfers};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
 use std::path::PathBuf;
 
 mod config;
 pub mod connection;
+mod delta;
+pub mod error;
+pub mod events;
 mod file_util;
+mod hash;
+pub mod options;
+pub mod plan;
+mod pool;
+mod pull;
+mod rebuild;
+pub mod remote;
+pub mod report;
 mod sftp;
+mod transfer;
+mod twoway;
+mod verify;
 
-pub fn clone_dir(ssh: &SshCred<'static>, src: &Path, dest: &Path) -> Result<(), Error> {
-    let conn = ssh.connect()?;
-    let sftp_conn = SftpSync::new(conn)?;
-    sftp_conn.download_item(src, dest)?;
-    Ok(())
+pub fn clone_dir(ssh: &SshCred, src: &Path, dest: &Path) -> Result<(), Error> {
+    clone_dir_with_options(ssh, src, dest, &SyncOptions::default())
+}
+
+pub fn clone_dir_with_options(
+    ssh: &SshCred,
+    src: &Path,
+    dest: &Path,
+    options: &SyncOptions,
+) -> Result<(), Error> {
+    let events = Events(options.events.as_deref());
+    events.check(
+        SftpSync::connect(ssh)
+            .and_then(|sftp_conn| sftp_conn.download_item(src, dest, events, options)),
+    )
 }
 
 pub fn clone_file(
-    ssh: &SshCred<'static>,
+    ssh: &SshCred,
     src: &Path,
     dest: &Path,
     config_dest: Option<&Path>,
 ) -> Result<(), Error> {
-    let conn = ssh.connect()?;
-    let sftp_conn = SftpSync::new(conn)?;
-    sftp_conn.download_file(src, dest, config_dest)?;
-    Ok(())
+    clone_file_with_options(ssh, src, dest, config_dest, &SyncOptions::default())
 }
 
-pub fn sync(ssh: &SshCred<'static>, src: &Path, dest: Option<&Path>) -> Result<(), Error> {
-    //get toml config file
-    if check_if_dir(&src)? {
-        create_checksum_file(src)?;
+pub fn clone_file_with_options(
+    ssh: &SshCred,
+    src: &Path,
+    dest: &Path,
+    config_dest: Option<&Path>,
+    options: &SyncOptions,
+) -> Result<(), Error> {
+    let events = Events(options.events.as_deref());
+    events.check(
+        SftpSync::connect(ssh).and_then(|sftp_conn| match config_dest {
+            Some(config_dest) => {
+                let config = OpenConfig::open(config_dest)?;
+                sftp_conn.download_file(src, dest, Some(config.local()), events, options)?;
+                config.close()
+            }
+            None => sftp_conn.download_file(src, dest, None, events, options),
+        }),
+    )
+}
 
-        let config_str = read_checksum_file(src)?;
-        match parse_checksum_config(&config_str) {
-            Ok(parsed_config) => {
-                let conn = ssh.connect()?;
-                let sftp_conn = SftpSync::new(conn)?;
+pub fn sync(ssh: &SshCred, src: &Path, dest: Option<&Path>) -> Result<SyncReport, Error> {
+    sync_with_options(ssh, src, dest, &SyncOptions::default())
+}
 
-                //get all sub dir and removed ignored dir
-                let ignore_files = get_ignore_file(src)?;
+pub fn sync_with_options(
+    ssh: &SshCred,
+    src: &Path,
+    dest: Option<&Path>,
+    options: &SyncOptions,
+) -> Result<SyncReport, Error> {
+    let events = Events(options.events.as_deref());
+    //check if dest path is set
+    let dest_path = dest.unwrap_or_else(|| Path::new(""));
+    events.check(run_sync(ssh, src, dest_path, options, events))
+}
 
-                let mut dir = get_all_subdir(&src.to_str().unwrap())?;
+fn run_sync(
+    ssh: &SshCred,
+    src: &Path,
+    dest_path: &Path,
+    options: &SyncOptions,
+    events: Events,
+) -> Result<SyncReport, Error> {
+    let target = target_key(ssh, dest_path);
+    //locked before planning so a run started meanwhile can't change the config under it
+    let config = OpenConfig::open(config_dir(src)?)?;
+    let (config_dir, plan) = plan_sync(src, dest_path, Some(&target), Some(&config), options)?;
+    create_checksum_file(&config_dir)?;
+    let pool = SftpPool::connect(ssh, options.workers)?;
+    let report = apply_plan(
+        &plan,
+        dest_path,
+        config.remote(&target),
+        &pool,
+        events,
+        options,
+    )?;
+    config.close()?;
+    Ok(report)
+}
 
-                let mut dyn_path = String::from("");
-                let mut dyn_vec = vec![];
+///Sync src to the remote called name in `.xsync/config.toml`, next to the `.xsync.toml` of src
+///
+///The remote gives the server, credentials and destination folder, and its ignore and include
+///rules are added to the `.xsyncignore` files
+pub fn sync_remote(src: &Path, name: &str) -> Result<SyncReport, Error> {
+    sync_remote_with_options(src, name, &SyncOptions::default())
+}
 
-                //get all folder component of parent path and add them to dir list
-                let path_component = src.components();
-                for i in path_component {
-                    dyn_path += &format!("{}/", i.as_os_str().to_str().unwrap()).to_string();
-                    dyn_vec.push(Path::new(&dyn_path).to_path_buf());
-                }
+pub fn sync_remote_with_options(
+    src: &Path,
+    name: &str,
+    options: &SyncOptions,
+) -> Result<SyncReport, Error> {
+    let events = Events(options.events.as_deref());
+    let run = || {
+        let remote = remote::find_remote(config_dir(src)?, name)?;
+        let ssh = remote.credentials()?;
+        run_sync(
+            &ssh,
+            src,
+            remote.dest_path(),
+            &remote.options(options),
+            events,
+        )
+    };
+    events.check(run())
+}
 
-                remove_ignored_path(src, &mut dir, &ignore_files);
-                dyn_vec.append(&mut dir);
-
-                //get folders to delete and upload
-                let delete_folder = get_items_to_delete(&parsed_config.folders, &dyn_vec);
-                let upload_folder = get_items_to_upload(&parsed_config.folders, &dyn_vec);
-
-                let mut file_list = (get_all_files_subdir(&src.to_str().unwrap()))?;
-                remove_ignored_path(src, &mut file_list, &ignore_files);
-                //get files to be deleted and upload
-                let delete_files = get_items_to_delete(&parsed_config.files, &file_list);
-                let upload_files = get_items_to_upload(&parsed_config.files, &file_list);
-
-                //check if dest path is set
-                match dest {
-                    Some(dest_path) => {
-                        upload_and_sync(
-                            &parsed_config,
-                            &dest_path,
-                            &src,
-                            &sftp_conn,
-                            upload_folder,
-                            delete_folder,
-                            upload_files,
-                            delete_files,
-                            file_list,
-                        )?;
-                    }
-                    None => {
-                        upload_and_sync(
-                            &parsed_config,
-                            Path::new(""),
-                            &src,
-                            &sftp_conn,
-                            upload_folder,
-                            delete_folder,
-                            upload_files,
-                            delete_files,
-                            file_list,
-                        )?;
-                    }
-                }
-            }
-            Err(err) => {
-                eprintln!("error parsing toml config:\n {:?}", err)
-            }
+//the folder whose `.xsync.toml` tracks src
+fn config_dir(src: &Path) -> Result<&Path, Error> {
+    if check_if_dir(src)? {
+        return Ok(src);
+    }
+    match src.parent().unwrap() {
+        parent if parent.as_os_str().is_empty() => Ok(Path::new("./")),
+        parent => Ok(parent),
+    }
+}
+
+///Download what changed under the remote path src since the last pull into dest
+///
+///Files are laid out in dest like [`clone_dir`] does and tracked in dest's `.xsync.toml`.
+///Only files missing locally or differing from the server are downloaded,
+///set `delete_local` in the options to also remove what was removed from the server
+pub fn sync_pull(ssh: &SshCred, src: &Path, dest: &Path) -> Result<SyncReport, Error> {
+    sync_pull_with_options(ssh, src, dest, &SyncOptions::default())
+}
+
+pub fn sync_pull_with_options(
+    ssh: &SshCred,
+    src: &Path,
+    dest: &Path,
+    options: &SyncOptions,
+) -> Result<SyncReport, Error> {
+    let events = Events(options.events.as_deref());
+    events.check(run_pull(ssh, src, dest, options, events))
+}
+
+fn run_pull(
+    ssh: &SshCred,
+    src: &Path,
+    dest: &Path,
+    options: &SyncOptions,
+    events: Events,
+) -> Result<SyncReport, Error> {
+    let pool = SftpPool::connect(ssh, options.workers)?;
+    let tree = pool.primary().list_all(src, options.symlinks, events)?;
+    fs::create_dir_all(dest).at(dest)?;
+    create_checksum_file(dest)?;
+    let config = OpenConfig::open(dest)?;
+    let parsed_config = load_checksum_config(config.local())?;
+    let plan = pull::plan_pull(&tree, src, dest, &parsed_config, options)?;
+    let report = pull::apply_pull(&plan, &tree, config.local(), &pool, events, options)?;
+    config.close()?;
+    Ok(report)
+}
+
+///Sync the folder src both ways, using `.xsync.toml` as the state both sides last agreed on
+///
+///A file changed on one side only is copied to the other side. Files changed on both sides
+///are resolved with the `conflict` policy of the options, by default nothing is changed
+///and [`error::Error::Conflict`] lists them
+pub fn sync_two_way(ssh: &SshCred, src: &Path, dest: Option<&Path>) -> Result<TwoWayReport, Error> {
+    sync_two_way_with_options(ssh, src, dest, &SyncOptions::default())
+}
+
+pub fn sync_two_way_with_options(
+    ssh: &SshCred,
+    src: &Path,
+    dest: Option<&Path>,
+    options: &SyncOptions,
+) -> Result<TwoWayReport, Error> {
+    let events = Events(options.events.as_deref());
+    let dest_path = dest.unwrap_or_else(|| Path::new(""));
+    events.check(twoway::run_two_way(ssh, src, dest_path, options, events))
+}
+
+///Check the server against what `.xsync.toml` recorded for src and send again what drifted
+///
+///Files are compared by size, then hashed on the server when it has a command for their hash
+///(`b3sum` or `sha256sum`), links by their target. Missing or modified items are uploaded again,
+///local changes made since the last sync are left for the next [`sync`]
+pub fn verify(ssh: &SshCred, src: &Path, dest: Option<&Path>) -> Result<DriftReport, Error> {
+    verify_with_options(ssh, src, dest, &SyncOptions::default())
+}
+
+pub fn verify_with_options(
+    ssh: &SshCred,
+    src: &Path,
+    dest: Option<&Path>,
+    options: &SyncOptions,
+) -> Result<DriftReport, Error> {
+    let events = Events(options.events.as_deref());
+    let dest_path = dest.unwrap_or_else(|| Path::new(""));
+    events.check(verify::run_verify(ssh, src, dest_path, options, events))
+}
+
+///Write `.xsync.toml` again for src from what the server already has at dest
+///
+///Local files whose copy on the server has the same size and hash are recorded as synced,
+///so the next [`sync`] only sends what differs. What was recorded for this server before is
+///dropped, and a `.xsync.toml` that can't be parsed is moved to `.xsync.toml.broken`
+pub fn rebuild(ssh: &SshCred, src: &Path, dest: Option<&Path>) -> Result<RebuildReport, Error> {
+    rebuild_with_options(ssh, src, dest, &SyncOptions::default())
+}
+
+pub fn rebuild_with_options(
+    ssh: &SshCred,
+    src: &Path,
+    dest: Option<&Path>,
+    options: &SyncOptions,
+) -> Result<RebuildReport, Error> {
+    let events = Events(options.events.as_deref());
+    let dest_path = dest.unwrap_or_else(|| Path::new(""));
+    events.check(rebuild::run_rebuild(ssh, src, dest_path, options))
+}
+
+///Work out what [`sync`] would change, without connecting to the server or writing `.xsync.toml`
+pub fn dry_run(src: &Path, dest: Option<&Path>) -> Result<SyncPlan, Error> {
+    dry_run_with_options(src, dest, &SyncOptions::default())
+}
+
+pub fn dry_run_with_options(
+    src: &Path,
+    dest: Option<&Path>,
+    options: &SyncOptions,
+) -> Result<SyncPlan, Error> {
+    let dest_path = dest.unwrap_or_else(|| Path::new(""));
+    let target = target_for_dest(config_dir(src)?, dest_path)?;
+    let (_, plan) = plan_sync(src, dest_path, target.as_deref(), None, options)?;
+    Ok(plan)
+}
+
+///Work out what [`sync`] to the server of ssh would change, without connecting to it
+///
+///[`dry_run`] only knows dest, and fails when src was synced to it on several servers
+pub fn dry_run_to(ssh: &SshCred, src: &Path, dest: Option<&Path>) -> Result<SyncPlan, Error> {
+    dry_run_to_with_options(ssh, src, dest, &SyncOptions::default())
+}
+
+pub fn dry_run_to_with_options(
+    ssh: &SshCred,
+    src: &Path,
+    dest: Option<&Path>,
+    options: &SyncOptions,
+) -> Result<SyncPlan, Error> {
+    let dest_path = dest.unwrap_or_else(|| Path::new(""));
+    let target = target_key(ssh, dest_path);
+    let (_, plan) = plan_sync(src, dest_path, Some(&target), None, options)?;
+    Ok(plan)
+}
+
+//returns the dir holding the toml config for src and the changes to sync it
+//target is the key of the server state in the config, if known
+//what the last push to target recorded in the toml config of dir, nothing without a target.
+//the state kept without a target belongs to pulls and clones and is never pushed from.
+//open holds the config with the updates of the run not written to the file yet
+fn pushed_state(
+    dir: &Path,
+    target: Option<&str>,
+    open: Option<&OpenConfig>,
+) -> Result<Config, Error> {
+    match target {
+        Some(target) => load_checksum_config(Manifest {
+            dir,
+            target: Some(target),
+            open,
+        }),
+        None => Ok(Config::default()),
+    }
+}
+
+fn plan_sync(
+    src: &Path,
+    dest_path: &Path,
+    target: Option<&str>,
+    open: Option<&OpenConfig>,
+    options: &SyncOptions,
+) -> Result<(PathBuf, SyncPlan), Error> {
+    let mut plan = SyncPlan::default();
+    if check_if_dir(src)? {
+        let parsed_config = pushed_state(src, target, open)?;
+
+        //get all sub dir and removed ignored dir
+        let ignore_files = get_ignore_file(src, &options.ignore)?;
+
+        let tree = list_local(src, options.symlinks)?;
+        let mut dir = tree.folders;
+
+        let mut dyn_path = String::from("");
+        let mut dyn_vec = vec![];
+
+        //get all folder component of parent path and add them to dir list
+        let path_component = src.components();
+        for i in path_component {
+            dyn_path += &format!("{}/", i.as_os_str().to_str().unwrap()).to_string();
+            dyn_vec.push(Path::new(&dyn_path).to_path_buf());
+        }
+
+        remove_ignored_path(&mut dir, &ignore_files);
+        dyn_vec.append(&mut dir);
+
+        let mut file_list = tree.files;
+        remove_ignored_path(&mut file_list, &ignore_files);
+        let mut links = tree.links;
+        links.retain(|(link, _)| !ignore_files.is_ignored(link, false));
+        plan.skipped_links = tree.skipped_links;
+        plan.skipped_links
+            .retain(|link| !ignore_files.is_ignored(link, false));
+        //links are recorded along with files
+        let mut synced_files = file_list.clone();
+        synced_files.extend(links.iter().map(|(link, _)| link.clone()));
+
+        //get folders and files to upload and delete
+        for i in get_items_to_upload(&parsed_config.folders, &dyn_vec) {
+            let remote = dest_path.join(&i);
+            plan.push(Action::CreateFolder, PathBuf::from(i), remote, 0);
+        }
+        //parents of src are left as they are on the server
+        plan.synced_folders = dyn_vec
+            .iter()
+            .filter(|i| i.starts_with(src))
+            .filter(|i| parsed_config.folders.contains_key(i.to_str().unwrap()))
+            .cloned()
+            .collect();
+        //only what was synced from src is deleted, folders are removed with their content
+        let under_src = |i: &String| Path::new(i).starts_with(src);
+        let files_to_delete = get_items_to_delete(&parsed_config.files, &synced_files);
+        for i in files_to_delete.into_iter().filter(under_src) {
+            let remote = dest_path.join(&i);
+            plan.push(Action::DeleteFile, PathBuf::from(i), remote, 0);
+        }
+        let folders_to_delete = get_items_to_delete(&parsed_config.folders, &dyn_vec);
+        for i in folders_to_delete.into_iter().filter(under_src) {
+            let remote = dest_path.join(&i);
+            plan.push(Action::DeleteFolder, PathBuf::from(i), remote, 0);
+        }
+        for i in &file_list {
+            plan_file(&parsed_config, i, dest_path, options, &mut plan)?;
         }
+        for (link, target) in &links {
+            plan_link(&parsed_config, link, target, dest_path, &mut plan);
+        }
+        Ok((src.to_path_buf(), plan))
     } else {
-        //get parent path and parse it
+        //get parent path, the toml config lives there
         let parent = src.parent().unwrap().to_str().unwrap();
-        let mut config_str = String::from("");
-        //create toml config in parent path and read to string
-        if parent == "" {
-            create_checksum_file(Path::new("./")).unwrap();
-            config_str += &read_checksum_file(Path::new("./"))?;
+        let config_dir = if parent.is_empty() {
+            Path::new("./")
         } else {
-            create_checksum_file(Path::new(parent)).unwrap();
-            config_str += &read_checksum_file(Path::new(parent))?;
+            Path::new(parent)
+        };
+        let parsed_config = pushed_state(config_dir, target, open)?;
+
+        //every folder of the parent path is created on the server
+        let mut dyn_path = String::from("");
+        for i in Path::new(parent).components() {
+            dyn_path += &format!("{}/", i.as_os_str().to_str().unwrap()).to_string();
+            if !parsed_config.folders.contains_key(&dyn_path) {
+                let remote = dest_path.join(&dyn_path);
+                plan.push(Action::CreateFolder, PathBuf::from(&dyn_path), remote, 0);
+            }
         }
-        // let config_str = read_checksum_file(src)?;
-        let conn = ssh.connect()?;
-        let sftp_conn = SftpSync::new(conn)?;
-        match parse_checksum_config(&config_str) {
-            Ok(parsed_config) => match dest {
-                Some(dest_path) => {
-                    sync_file(
-                        &parsed_config,
-                        &src,
-                        &dest_path,
-                        &sftp_conn,
-                        Path::new(parent),
-                    )?;
-                    return Ok(());
-                }
-                None => {
-                    sync_file(
-                        &parsed_config,
-                        &src,
-                        &Path::new(""),
-                        &sftp_conn,
-                        Path::new(parent),
-                    )?;
-                    return Ok(());
-                }
+        if !fs::symlink_metadata(src).at(src)?.is_symlink() {
+            plan_file(&parsed_config, src, dest_path, options, &mut plan)?;
+            return Ok((config_dir.to_path_buf(), plan));
+        }
+        let root = fs::canonicalize(config_dir).at(config_dir)?;
+        match options.symlinks {
+            SymlinkPolicy::Follow => plan_file(&parsed_config, src, dest_path, options, &mut plan)?,
+            SymlinkPolicy::Recreate => match local_link_target(src, &root)? {
+                Some(target) => plan_link(&parsed_config, src, &target, dest_path, &mut plan),
+                None => plan.skipped_links.push(src.to_path_buf()),
             },
-            Err(err) => {
-                eprintln!("error parsing toml config:\n {:?}", err)
-            }
+            SymlinkPolicy::Skip => plan.skipped_links.push(src.to_path_buf()),
         }
+        Ok((config_dir.to_path_buf(), plan))
     }
+}
 
+//add a file to the plan if it is new or its checksum differs from the toml config
+fn plan_file(
+    parsed_config: &Config,
+    src: &Path,
+    dest_path: &Path,
+    options: &SyncOptions,
+    plan: &mut SyncPlan,
+) -> Result<(), Error> {
+    let key = src.to_str().unwrap();
+    let action = match parsed_config.files.get(key) {
+        //remove the link first or the upload would write to what it points to
+        Some(entry) if entry.checksum.starts_with(LINK_PREFIX) => {
+            let remote = dest_path.join(src);
+            plan.push(Action::DeleteFile, src.to_path_buf(), remote, 0);
+            Action::CreateFile
+        }
+        Some(_) => match compare_recorded(src, key, parsed_config, options)? {
+            Recorded::Changed => Action::UpdateFile,
+            Recorded::Unchanged => {
+                plan.unchanged.push(src.to_path_buf());
+                return Ok(());
+            }
+            Recorded::Refresh(checksum, stamp) => {
+                plan.refreshed.push((src.to_path_buf(), checksum, stamp));
+                plan.unchanged.push(src.to_path_buf());
+                return Ok(());
+            }
+        },
+        None => Action::CreateFile,
+    };
+    let remote = PathBuf::new().join(dest_path).join(src);
+    plan.push(action, src.to_path_buf(), remote, get_file_size(src)?);
     Ok(())
 }
 
-fn sync_file(
+//add a link to the plan if it is new or its target changed since the last sync
+fn plan_link(
     parsed_config: &Config,
     src: &Path,
+    target: &Path,
     dest_path: &Path,
-    sftp_conn: &SftpSync,
-    parent: &Path,
-) -> Result<(), Error> {
-    if parent.to_str().unwrap() != "" {
-        let path_component = Path::new(parent).components();
-        let mut dyn_path = String::from("");
-        for i in path_component {
-            dyn_path += &format!("{}/", i.as_os_str().to_str().unwrap()).to_string();
-            create_and_add_folder(
-                &Path::new(&dyn_path),
-                &dest_path,
-                &sftp_conn,
-                &Path::new(parent),
-            )?
+    plan: &mut SyncPlan,
+) {
+    let action = match parsed_config.files.get(src.to_str().unwrap()) {
+        Some(entry) if entry.checksum == link_value(target) => {
+            plan.unchanged.push(src.to_path_buf());
+            return;
         }
-    }
+        Some(_) => Action::UpdateFile,
+        None => Action::CreateFile,
+    };
+    let remote = PathBuf::new().join(dest_path).join(src);
+    plan.push(action, src.to_path_buf(), remote, 0);
+}
 
-    //resolve parent path if none if detected
-    if parent.to_str().unwrap() == "" {
-        let parent = Path::new("./");
-        update_file(&parsed_config, &src, &dest_path, &sftp_conn, &parent)?;
-        return Ok(());
-    } else {
-        update_file(&parsed_config, &src, &dest_path, &sftp_conn, &parent)?;
-        return Ok(());
-    }
+fn link_value(target: &Path) -> String {
+    format!("{}{}", LINK_PREFIX, target.to_str().unwrap())
 }
 
-fn update_file(
-    parsed_config: &Config,
+//result of uploading a single file
+struct SentFile {
+    //value recorded in the toml config, the checksum for files
+    checksum: String,
+    //block signature of the uploaded content, links have none
+    signature: Option<delta::Signature>,
+    //stamp of the local file taken before it was read, links have none
+    stamp: Option<Stamp>,
+    bytes: u64,
+}
+
+//upload a file and return its checksum and block signature
+//nothing is written to the config here so this can run on any worker
+fn send_file(
     src: &Path,
     dest_path: &Path,
     sftp_conn: &SftpSync,
-    parent: &Path,
-) -> Result<(), Error> {
-    let file_content = read_file(src)?;
-    let checksum_data = create_checksum(&file_content[..]);
-
-    //check if tpml config has file
-    match parsed_config
-        .files
-        .get(&format!("{}", src.to_str().unwrap()))
+    manifest: Manifest,
+    resume: bool,
+    events: Events,
+    options: &SyncOptions,
+) -> Result<SentFile, Error> {
+    let filename = Path::new(src.to_str().unwrap());
+    let absolue_path = PathBuf::new().join(dest_path).join(filename);
+    if options.symlinks == SymlinkPolicy::Recreate
+        && fs::symlink_metadata(src).at(src)?.is_symlink()
     {
-        Some(config_checksum) => {
-            //check if found checksum equals config checksum
-            if &format!("{}", checksum_data) == config_checksum {
-                println!("no update made to file. Nothing new to update")
-            } else {
-                println!("upting file {:?}", src);
-                compute_and_add_file(
-                    &src,
-                    &dest_path,
-                    &file_content,
-                    checksum_data,
-                    &sftp_conn,
-                    &parent,
-                )?;
-            }
-            return Ok(());
+        let root = fs::canonicalize(manifest.dir).at(manifest.dir)?;
+        let target = local_link_target(src, &root)?.ok_or_else(|| Error::Io {
+            path: src.to_path_buf(),
+            source: std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "link leads outside the synced folder",
+            ),
+        })?;
+        sftp_conn.create_link(&absolue_path, &target)?;
+        return Ok(SentFile {
+            checksum: link_value(&target),
+            signature: None,
+            stamp: None,
+            bytes: 0,
+        });
+    }
+
+    //taken first so a write during the upload is seen by the next sync
+    let stamp = local_stamp(src)?;
+    let size = get_file_size(src)?;
+    events.emit(SyncEvent::FileStarted { path: src, size });
+
+    //send only the changed blocks when the remote copy still hashes to what we last uploaded,
+    //a copy edited on the server or that can't be hashed there is replaced in full
+    //the checksum is computed while the file streams through
+    let delta_sent = match delta::read_signature(manifest, src) {
+        Some(old_signature)
+            if sftp_conn.is_uploaded_copy(&absolue_path, &old_signature, src, events)? =>
+        {
+            let mut reader = ChecksumReader::new(fs::File::open(src).at(src)?, options.hash);
+            let delta = old_signature.diff(&mut reader).at(src)?;
+            sftp_conn
+                .write_blocks(&absolue_path, src, &delta, size, events)?
+                .map(|bytes| SentFile {
+                    checksum: reader.checksum(),
+                    bytes,
+                    signature: Some(delta.signature),
+                    stamp,
+                })
         }
+        _ => None,
+    };
+    let mut sent = match delta_sent {
+        Some(sent) => sent,
         None => {
-            println!("creating file {:?}", src);
-            compute_and_add_file(
-                &src,
-                &dest_path,
-                &file_content,
-                checksum_data,
-                &sftp_conn,
-                &parent,
-            )?;
-            return Ok(());
+            //what an interrupted upload left on the server is only read locally again
+            let offset = match resume {
+                true => sftp_conn.uploaded_prefix(&absolue_path, src, size, events)?,
+                false => 0,
+            };
+            let prefix = fs::File::open(src).at(src)?.take(offset);
+            let mut rest = fs::File::open(src).at(src)?;
+            rest.seek(SeekFrom::Start(offset)).at(src)?;
+            let mut reader = ChecksumReader::with_signature(
+                prefix.chain(ProgressReader::new(rest, src, offset, events)),
+                options.hash,
+                delta::block_size_for(size),
+            );
+            sftp_conn.create_file(&absolue_path, &mut reader, offset)?;
+            SentFile {
+                checksum: reader.checksum(),
+                signature: reader.into_signature(),
+                stamp,
+                bytes: size - offset,
+            }
         }
+    };
+    sftp_conn.copy_stat_to_remote(src, &absolue_path, options.preserve)?;
+    //the next delta trusts a remote copy that still has this size and mtime
+    if let Some(signature) = &mut sent.signature {
+        let stat = sftp_conn.sftp.stat(&absolue_path).at(&absolue_path)?;
+        signature.remote_stamp = remote_stamp(&stat);
     }
+    Ok(sent)
 }
 
-fn compute_and_add_file(
-    src: &Path,
-    dest_path: &Path,
-    file_content: &Vec<u8>,
-    checksum_data: u32,
-    sftp_conn: &SftpSync,
-    original_src: &Path,
-) -> Result<(), Error> {
-    let size = get_file_size(src)?;
-    let filename = Path::new(src.to_str().unwrap());
-    let absolue_path = PathBuf::new().join(dest_path).join(filename);
-    sftp_conn.create_file(&absolue_path, &size, None, &file_content[..])?;
-    //update config file after successful upload
+//update config file after successful upload
+fn record_file(src: &Path, sent: &SentFile, manifest: Manifest) -> Result<(), Error> {
+    match &sent.signature {
+        Some(signature) => delta::write_signature(manifest, src, signature)?,
+        None => delta::remove_signature(manifest, src)?,
+    }
     config::update_folder_config(
         "files",
-        &original_src,
-        &FolderConfig::Add(
+        manifest,
+        &FolderConfig::AddFile(
             String::from(src.to_str().unwrap()),
-            format!("{}", checksum_data),
+            sent.checksum.clone(),
+            sent.stamp,
         ),
-    )?;
-    Ok(())
+    )
 }
 
 fn compute_and_remove_file(
     src: &Path,
     dest_path: &Path,
     sftp_conn: &SftpSync,
-    original_src: &Path,
+    manifest: Manifest,
 ) -> Result<(), Error> {
-    let absolue_path = PathBuf::new().join(dest_path).join(&src);
+    let absolue_path = PathBuf::new().join(dest_path).join(src);
     sftp_conn.remove_file(&absolue_path)?;
+    delta::remove_signature(manifest, src)?;
     config::update_folder_config(
         "files",
-        &original_src,
-        &&FolderConfig::Remove(src.to_str().unwrap().to_string()),
+        manifest,
+        &FolderConfig::Remove(src.to_str().unwrap().to_string()),
     )?;
     Ok(())
 }
@@ -325,110 +622,154 @@ fn create_and_add_folder(
     src: &Path,
     dest_path: &Path,
     sftp_conn: &SftpSync,
-    original_src: &Path,
+    manifest: Manifest,
+    mode: u32,
 ) -> Result<(), Error> {
     let absolue_path = PathBuf::new().join(dest_path).join(src);
-    sftp_conn.create_folder(&absolue_path);
+    sftp_conn.create_folder(&absolue_path, mode)?;
     update_folder_config(
         "folders",
-        &original_src,
+        manifest,
         &FolderConfig::Add(src.to_str().unwrap().to_string(), "".to_string()),
     )?;
     Ok(())
 }
 
-//TODO: delete folder recursively fails
-fn _compute_and_remove_folder(
+fn compute_and_remove_folder(
     src: &Path,
     dest_path: &Path,
     sftp_conn: &SftpSync,
-    original_src: &Path,
+    manifest: Manifest,
 ) -> Result<(), Error> {
     let absolue_path = PathBuf::new().join(dest_path).join(src);
-    sftp_conn._remove_dir(&absolue_path)?;
+    sftp_conn.remove_dir_all(&absolue_path)?;
     config::update_folder_config(
         "folders",
-        &original_src,
-        &&FolderConfig::Remove(src.to_str().unwrap().to_string()),
+        manifest,
+        &FolderConfig::Remove(src.to_str().unwrap().to_string()),
     )?;
     Ok(())
 }
 
-fn upload_and_sync(
-    parsed_config: &Config,
+fn apply_plan(
+    plan: &SyncPlan,
     dest_path: &Path,
-    src: &Path,
-    sftp_conn: &SftpSync,
-    upload_folder: Vec<String>,
-    _delete_folder: Vec<String>,
-    upload_files: Vec<String>,
-    delete_files: Vec<String>,
-    file_list: Vec<PathBuf>,
-) -> Result<(), Error> {
-    //create destination path if not found in config
-    if !parsed_config
-        .folders
-        .contains_key(&String::from(dest_path.to_str().unwrap()))
-        && dest_path.to_str().unwrap() != ""
-    {
-        sftp_conn.create_folder(dest_path);
+    manifest: Manifest,
+    pool: &SftpPool,
+    events: Events,
+    options: &SyncOptions,
+) -> Result<SyncReport, Error> {
+    let sftp_conn = pool.primary();
+    let preserve = options.preserve;
+    //folders get the mode set in the options or the one of the local folder
+    let folder_mode = |local: &Path| match options.dir_mode {
+        Some(mode) => Ok(mode),
+        None => local_mode(local),
+    };
+    let mut report = SyncReport {
+        files_unchanged: plan.unchanged.clone(),
+        ..SyncReport::default()
+    };
+    for i in &plan.unchanged {
+        events.emit(SyncEvent::FileSkipped { path: i });
+    }
+    for i in &plan.skipped_links {
+        events.emit(SyncEvent::LinkSkipped { path: i });
+    }
+    for (key, checksum, stamp) in &plan.refreshed {
+        config::update_folder_config(
+            "files",
+            manifest,
+            &FolderConfig::AddFile(key.to_str().unwrap().to_string(), checksum.clone(), *stamp),
+        )?;
     }
 
-    //folders need to be created sequentially
+    //create destination path
+    if dest_path.to_str().unwrap() != "" {
+        let mode = options.dir_mode.unwrap_or(DEFAULT_DIR_MODE);
+        sftp_conn.create_folder_all(dest_path, mode)?;
+    }
+
+    //delete marked files, links replaced by a file or folder go first
+    for i in plan.with_action(Action::DeleteFile) {
+        compute_and_remove_file(&i.path, dest_path, sftp_conn, manifest)?;
+        events.emit(SyncEvent::Deleted { path: &i.path });
+        report.files_deleted.push(i.path.clone());
+    }
+
+    //folders need to be created sequentially, parents first
     //don't run with concurrency
+    let mut upload_folder: Vec<&PlanItem> = plan.with_action(Action::CreateFolder).collect();
+    upload_folder.sort_by_key(|i| i.path.components().count());
     for i in upload_folder {
-        create_and_add_folder(Path::new(&i), dest_path, &sftp_conn, &src)?;
+        let mode = folder_mode(&i.path)?;
+        create_and_add_folder(&i.path, dest_path, sftp_conn, manifest, mode)?;
+        events.emit(SyncEvent::FolderCreated { path: &i.path });
+        report.folders_created.push(i.path.clone());
     }
 
-    // delete marked folder
-    // for i in delete_folder {
-    //     compute_and_remove_folder(Path::new(&i), dest_path, &sftp_conn, &src)?;
-    // }
-
-    //delete marked files
-    for i in delete_files {
-        compute_and_remove_file(Path::new(&i), dest_path, &sftp_conn, &src)?;
+    //folders created before may have the wrong mode
+    for i in &plan.synced_folders {
+        sftp_conn.create_folder(&dest_path.join(i), folder_mode(i)?)?;
     }
 
-    //new files to upload
-    //TODO: create files concurrently on muntiple threads
-    for i in upload_files {
-        let file_content = read_file(&Path::new(&i))?;
-        let checksum_data = create_checksum(&file_content[..]);
-        println!("creating file {:?}", i);
-        compute_and_add_file(
-            &Path::new(&i),
-            &dest_path,
-            &file_content,
-            checksum_data,
-            &sftp_conn,
-            &src,
-        )?;
+    //delete marked folders from the deepest level up
+    //so a parent is never removed before its children
+    let mut delete_folder: Vec<&PlanItem> = plan.with_action(Action::DeleteFolder).collect();
+    delete_folder.sort_by_key(|i| std::cmp::Reverse(i.path.components().count()));
+    for i in delete_folder {
+        compute_and_remove_folder(&i.path, dest_path, sftp_conn, manifest)?;
+        events.emit(SyncEvent::Deleted { path: &i.path });
+        report.folders_deleted.push(i.path.clone());
     }
-    for i in &file_list {
-        let file_content = read_file(&i)?;
-        let checksum_data = create_checksum(&file_content[..]);
-        match parsed_config.files.get(&format!("{}", i.to_str().unwrap())) {
-            Some(config_checksum) => {
-                if &format!("{}", checksum_data) != config_checksum {
-                    println!("updating file {:?}", i);
-                    compute_and_add_file(
-                        &i,
-                        &dest_path,
-                        &file_content,
-                        checksum_data,
-                        &sftp_conn,
-                        &src,
-                    )?;
-                }
-            }
-            None => {
-                //do nothing
-                //already taken care of by the get_item_to_upload function above
+
+    //files are sent concurrently over every session in the pool,
+    //the config is only written from this thread as uploads complete
+    let send_list: Vec<&PlanItem> = plan
+        .items
+        .iter()
+        .filter(|i| matches!(i.action, Action::CreateFile | Action::UpdateFile))
+        .collect();
+    //uploads interrupted by an earlier run resume where they stopped
+    //when the local file didn't change since
+    let started = send_list
+        .iter()
+        .map(|i| Ok((i.remote.clone(), local_stamp(&i.path)?)))
+        .collect::<Result<Vec<_>, Error>>()?;
+    let mut transfers = Transfers::load(manifest)?;
+    let resume = transfers.start_uploads(sftp_conn, &started);
+    transfers.save(manifest)?;
+    pool.run(
+        send_list,
+        |sftp_conn, i| {
+            let resume = resume.contains(&i.remote);
+            send_file(
+                &i.path, dest_path, sftp_conn, manifest, resume, events, options,
+            )
+        },
+        |i, sent| {
+            record_file(&i.path, &sent, manifest)?;
+            events.emit(SyncEvent::FileFinished {
+                path: &i.path,
+                bytes: sent.bytes,
+            });
+            report.bytes_transferred += sent.bytes;
+            if i.action == Action::UpdateFile {
+                report.files_updated.push(i.path.clone());
+            } else {
+                report.files_created.push(i.path.clone());
             }
-        }
+            Ok(())
+        },
+    )?;
+    transfers.finish_uploads();
+    transfers.save(manifest)?;
+
+    //folder metadata is set once their content is written
+    for i in plan.with_action(Action::CreateFolder) {
+        sftp_conn.copy_stat_to_remote(&i.path, &i.remote, preserve)?;
     }
-    Ok(())
+    Ok(report)
 }
 
 #[cfg(test)]
@@ -437,6 +778,123 @@ mod tests {
     use std::env;
     use std::fs;
 
+    #[test]
+    fn test_remove_dir_all() {
+        let (Some(u), Some(p)) = (env::var_os("NAME"), env::var_os("PASS")) else {
+            println!("skipping remove_dir_all() test...");
+            return;
+        };
+        let ssh = SshCred::new(
+            AuthOption::UserauthPassword(
+                u.to_str().unwrap().to_string(),
+                p.to_str().unwrap().to_string(),
+            ),
+            "127.0.0.1".to_string(),
+            "22".to_string(),
+        );
+        let sftp_conn = SftpSync::connect(&ssh).unwrap();
+        let root = Path::new("test_remove_dir_all");
+        sftp_conn
+            .create_folder_all(&root.join("sub/deep"), 0o755)
+            .unwrap();
+        for file in ["file", "sub/file", "sub/deep/file"] {
+            sftp_conn.sftp.create(&root.join(file)).unwrap();
+        }
+        sftp_conn.remove_dir_all(root).unwrap();
+        assert!(sftp_conn.sftp.stat(root).is_err());
+        //a folder already gone is not an error
+        sftp_conn.remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let root = Path::new("test_dry_run");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub/file"), "dry run").unwrap();
+
+        let plan = dry_run(root, Some(Path::new("dest"))).unwrap();
+        assert!(plan.items.contains(&PlanItem {
+            action: Action::CreateFile,
+            path: root.join("sub/file"),
+            remote: Path::new("dest").join(root).join("sub/file"),
+            size: 7,
+        }));
+        assert_eq!(plan.with_action(Action::CreateFolder).count(), 2);
+        assert!(plan.synced_folders.is_empty());
+        assert_eq!(plan.upload_size(), 7);
+        //nothing is written locally
+        assert!(!root.join(config::CHECKSUM_FILE).exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_push_after_pull() {
+        let root = Path::new("test_push_after_pull");
+        fs::create_dir_all(root).unwrap();
+        fs::write(root.join("a"), "pulled").unwrap();
+        //what sync_pull(ssh, "www", root) records
+        create_checksum_file(root).unwrap();
+        let pulled = Manifest::local(root);
+        let folder = FolderConfig::Add("www".to_string(), "".to_string());
+        update_folder_config("folders", pulled, &folder).unwrap();
+        let file = FolderConfig::Add("www/a".to_string(), "blake3:00".to_string());
+        update_folder_config("files", pulled, &file).unwrap();
+
+        let plan = dry_run(root, None);
+        let ssh = SshCred::new(
+            AuthOption::UserauthAgent("user".to_string()),
+            "host".to_string(),
+            "22".to_string(),
+        );
+        let plan_to = dry_run_to(&ssh, root, Some(Path::new("www")));
+        fs::remove_dir_all(root).unwrap();
+
+        //nothing pulled is deleted, everything is sent as new
+        for plan in [plan.unwrap(), plan_to.unwrap()] {
+            assert_eq!(plan.with_action(Action::DeleteFile).count(), 0);
+            assert_eq!(plan.with_action(Action::DeleteFolder).count(), 0);
+            assert_eq!(plan.with_action(Action::CreateFile).count(), 1);
+            assert!(!plan.to_string().contains("//"));
+        }
+    }
+
+    #[test]
+    fn test_deletes_under_src() {
+        let root = Path::new("test_deletes_under_src");
+        fs::create_dir_all(root).unwrap();
+        fs::write(root.join("kept"), "kept").unwrap();
+        create_checksum_file(root).unwrap();
+        let config = OpenConfig::open(root).unwrap();
+        let pushed = config.remote("host:22:");
+        for folder in [
+            "test_deletes_under_src/",
+            "test_deletes_under_src/gone",
+            "other",
+        ] {
+            let action = FolderConfig::Add(folder.to_string(), "".to_string());
+            update_folder_config("folders", pushed, &action).unwrap();
+        }
+        for file in ["test_deletes_under_src/gone/file", "other/file"] {
+            let action = FolderConfig::Add(file.to_string(), "blake3:00".to_string());
+            update_folder_config("files", pushed, &action).unwrap();
+        }
+        config.close().unwrap();
+
+        let plan = dry_run(root, None);
+        fs::remove_dir_all(root).unwrap();
+
+        let plan = plan.unwrap();
+        let deleted: Vec<&Path> = plan
+            .items
+            .iter()
+            .filter(|i| matches!(i.action, Action::DeleteFile | Action::DeleteFolder))
+            .map(|i| i.path.as_path())
+            .collect();
+        assert_eq!(deleted.len(), 2);
+        assert!(deleted.contains(&root.join("gone").as_path()));
+        assert!(deleted.contains(&root.join("gone/file").as_path()));
+    }
+
     #[test]
     fn test_sync() {
         match env::var_os("NAME") {
@@ -474,14 +932,15 @@ mod tests {
                     sync(&ssh, Path::new("test_sync"), None).unwrap();
                     //clone folder back to dir
                     clone_dir(&ssh, Path::new("test_sync"), Path::new("test_sync_2")).unwrap();
-                    let dir = get_all_subdir("test_sync_2").unwrap();
+                    let tree = list_local(Path::new("test_sync_2"), SymlinkPolicy::Follow).unwrap();
+                    let dir = tree.folders;
                     assert!(dir.contains(&Path::new("test_sync_2/test_sync").to_path_buf()));
                     assert!(dir.contains(&Path::new("test_sync_2/test_sync/test2").to_path_buf()));
                     assert!(
                         dir.contains(&Path::new("test_sync_2/test_sync/test2/test3").to_path_buf())
                     );
 
-                    let files = get_all_files_subdir("test_sync_2").unwrap();
+                    let files = tree.files;
                     assert!(
                         files.contains(&Path::new("test_sync_2/test_sync/keep.txt").to_path_buf())
                     );
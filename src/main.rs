@@ -1,15 +1,511 @@
-use std::path::Path;
-use rxsync::{connection::*, sync};
-
-fn main() {
-    let conn = SshCred::new(
-        AuthOption::UserauthPassword("".to_string(), "".to_string()),
-        "127.0.0.1".to_string(),
-        "22".to_string(),
+use rxsync::{
+    clone_dir_with_options, clone_file_with_options,
+    connection::*,
+    dry_run_to_with_options, dry_run_with_options,
+    error::Error,
+    events::{EventHandler, PrintEvents, SyncEvent},
+    options::SyncOptions,
+    plan::Action,
+    plan::SyncPlan,
+    rebuild_with_options,
+    remote::{load_remotes, remotes_file_path, Remote},
+    report::{RebuildReport, SyncReport},
+    sync_pull_with_options, sync_with_options,
+};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+
+const USAGE: &str = "usage: rxsync <command> [options] <args>
+
+commands:
+  push <local> <[user@]host:path>     upload what changed locally since the last push
+  pull <[user@]host:path> <local>     download what changed on the server since the last pull
+  clone <[user@]host:path> <local>    copy a remote folder, or a file with --file
+  status <local> [[user@]host:path]   count what a push would change, without connecting
+  diff <local> [[user@]host:path]     list what a push would change, without connecting
+  rebuild <local> <[user@]host:path>  write .xsync.toml again from what the server has
+
+  an IPv6 host is written in brackets, [user@][host]:path
+  a target without a host can be the name of a remote in the .xsync/config.toml of <local>
+
+options:
+  -H, --host <host>    server to use when the target has no host
+  -p, --port <port>    ssh port, 22 by default
+  -u, --user <user>    user to log in as when the target has none, $USER by default
+  -i, --key <file>     private key to log in with instead of the ssh agent
+  -j, --workers <n>    number of ssh sessions used for transfers
+      --delete         pull: remove local files that were removed from the server
+      --file           clone: the remote path is a file
+  -q, --quiet          only print errors and the summary
+
+exit codes:
+  0  done, or nothing to push for status and diff
+  1  status and diff found changes to push
+  2  invalid arguments
+  3  could not connect or log in
+  4  a transfer or a remote operation failed
+  5  a local file, .xsync.toml or .xsyncignore could not be read or written
+  6  files changed both locally and on the server since the last sync";
+
+const EXIT_CHANGES: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_CONNECTION: u8 = 3;
+const EXIT_REMOTE: u8 = 4;
+const EXIT_LOCAL: u8 = 5;
+const EXIT_CONFLICT: u8 = 6;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Push,
+    Pull,
+    Clone,
+    Status,
+    Diff,
+    Rebuild,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Args {
+    host: Option<String>,
+    port: Option<String>,
+    user: Option<String>,
+    key: Option<PathBuf>,
+    workers: Option<usize>,
+    delete: bool,
+    file: bool,
+    quiet: bool,
+    paths: Vec<String>,
+}
+
+//a `[user@]host:path` argument, like rsync a colon before any slash makes it remote
+//an IPv6 host is written in brackets, `[user@][host]:path`
+#[derive(Debug, PartialEq, Eq)]
+struct Target {
+    user: Option<String>,
+    host: Option<String>,
+    path: PathBuf,
+}
+
+impl Target {
+    fn parse(arg: &str) -> Target {
+        let remote = match arg.split_once('[') {
+            //the colons of the address are inside the brackets
+            Some((user, rest)) if user.is_empty() || user.ends_with('@') => rest
+                .split_once("]:")
+                .map(|(host, path)| (user.strip_suffix('@'), host, path)),
+            _ => arg
+                .split_once(':')
+                .map(|(host, path)| match host.rsplit_once('@') {
+                    Some((user, host)) => (Some(user), host, path),
+                    None => (None, host, path),
+                }),
+        };
+        let remote = remote.filter(|(user, host, _)| {
+            !host.is_empty() && !host.contains('/') && !user.is_some_and(|user| user.contains('/'))
+        });
+        match remote {
+            Some((user, host, path)) => Target {
+                user: user.map(str::to_string),
+                host: Some(host.to_string()),
+                path: PathBuf::from(path),
+            },
+            None => Target {
+                user: None,
+                host: None,
+                path: PathBuf::from(arg),
+            },
+        }
+    }
+}
+
+//an empty path is the login folder on the server
+fn dest(path: &Path) -> Option<&Path> {
+    match path.as_os_str().is_empty() {
+        true => None,
+        false => Some(path),
+    }
+}
+
+//a target without a host can be the name of a remote in the `.xsync/config.toml` of local
+fn named_remote(local: &Path, target: &Target) -> Result<Option<Remote>, Error> {
+    let name = match (&target.host, target.path.to_str()) {
+        (None, Some(name)) if !name.is_empty() => name,
+        _ => return Ok(None),
+    };
+    let root = match local.is_dir() {
+        true => local,
+        false => local.parent().unwrap_or_else(|| Path::new("")),
+    };
+    if !remotes_file_path(root).is_file() {
+        return Ok(None);
+    }
+    Ok(load_remotes(root)?.remove(name))
+}
+
+fn parse_command(name: &str) -> Result<Command, String> {
+    match name {
+        "push" => Ok(Command::Push),
+        "pull" => Ok(Command::Pull),
+        "clone" => Ok(Command::Clone),
+        "status" => Ok(Command::Status),
+        "diff" => Ok(Command::Diff),
+        "rebuild" => Ok(Command::Rebuild),
+        _ => Err(format!("unknown command {:?}", name)),
+    }
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "-H" | "--host" => parsed.host = Some(value(&arg)?),
+            "-p" | "--port" => parsed.port = Some(value(&arg)?),
+            "-u" | "--user" => parsed.user = Some(value(&arg)?),
+            "-i" | "--key" => parsed.key = Some(PathBuf::from(value(&arg)?)),
+            "-j" | "--workers" => {
+                let workers = value(&arg)?;
+                match workers.parse() {
+                    Ok(workers) if workers > 0 => parsed.workers = Some(workers),
+                    _ => return Err(format!("invalid number of workers {:?}", workers)),
+                }
+            }
+            "--delete" => parsed.delete = true,
+            "--file" => parsed.file = true,
+            "-q" | "--quiet" => parsed.quiet = true,
+            "--" => parsed.paths.extend(args.by_ref()),
+            _ if arg.starts_with('-') && arg != "-" => {
+                return Err(format!("unknown option {:?}", arg))
+            }
+            _ => parsed.paths.push(arg),
+        }
+    }
+    Ok(parsed)
+}
+
+//the local and the remote argument of a command, in the order the command takes them
+fn paths(
+    args: &Args,
+    remote_first: bool,
+    remote_optional: bool,
+) -> Result<(PathBuf, Target), String> {
+    match (args.paths.as_slice(), remote_first) {
+        ([local], false) if remote_optional => Ok((PathBuf::from(local), Target::parse(""))),
+        ([local, remote], false) => Ok((PathBuf::from(local), Target::parse(remote))),
+        ([remote, local], true) => Ok((PathBuf::from(local), Target::parse(remote))),
+        _ => Err("wrong number of arguments".to_string()),
+    }
+}
+
+fn port(args: &Args) -> String {
+    args.port.clone().unwrap_or_else(|| "22".to_string())
+}
+
+fn credentials(args: &Args, target: &Target) -> Result<SshCred, String> {
+    let host = target
+        .host
+        .clone()
+        .or_else(|| args.host.clone())
+        .ok_or("no host given, use [user@]host:path or --host")?;
+    let user = target
+        .user
+        .clone()
+        .or_else(|| args.user.clone())
+        .or_else(|| env::var("USER").ok())
+        .ok_or("no user given, use user@host:path or --user")?;
+    let port = port(args);
+    let auth = match &args.key {
+        Some(key) => AuthOption::UserauthPubkeyFile(user, None, key.clone(), None),
+        None => AuthOption::UserauthAgent(user),
+    };
+    Ok(SshCred::new(auth, host, port))
+}
+
+//why a command stopped
+enum Failure {
+    Usage(String),
+    Sync(Error),
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Self {
+        Failure::Usage(message)
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(err: Error) -> Self {
+        Failure::Sync(err)
+    }
+}
+
+fn exit_code(err: &Error) -> u8 {
+    match err {
+        Error::Connection { .. } | Error::Authentication { .. } => EXIT_CONNECTION,
+        Error::Sftp { .. } | Error::Transfer { .. } => EXIT_REMOTE,
+        Error::Conflict { .. } => EXIT_CONFLICT,
+        Error::Io { .. }
+        | Error::Manifest { .. }
+        | Error::Config { .. }
+        | Error::Ignore { .. }
+        | Error::Locked { .. } => EXIT_LOCAL,
+    }
+}
+
+fn print_report(report: &SyncReport, verb: &str) {
+    println!(
+        "{} created, {} updated, {} deleted, {} unchanged, {} bytes {}",
+        report.files_created.len(),
+        report.files_updated.len(),
+        report.files_deleted.len() + report.folders_deleted.len(),
+        report.files_unchanged.len(),
+        report.bytes_transferred,
+        verb
     );
+}
+
+fn print_status(plan: &SyncPlan) {
+    if plan.is_empty() {
+        println!("up to date, {} files unchanged", plan.unchanged.len());
+        return;
+    }
+    let count = |action| plan.with_action(action).count();
+    println!(
+        "{} to create, {} to update, {} to delete, {} unchanged, {} bytes to send",
+        count(Action::CreateFile) + count(Action::CreateFolder),
+        count(Action::UpdateFile),
+        count(Action::DeleteFile) + count(Action::DeleteFolder),
+        plan.unchanged.len(),
+        plan.upload_size()
+    );
+}
+
+fn print_rebuild(report: &RebuildReport) {
+    if let Some(broken) = &report.moved_aside {
+        println!("{:?} could not be read and was moved aside", broken);
+    }
+    println!(
+        "{} matched, {} to send, {} only on the server",
+        report.matched.len(),
+        report.differing.len(),
+        report.remote_only.len()
+    );
+}
+
+fn run(command: Command, args: &Args) -> Result<u8, Failure> {
+    //errors are printed once the command returns
+    let print = |event: &SyncEvent| {
+        if !matches!(event, SyncEvent::Error { .. }) {
+            PrintEvents.handle(event)
+        }
+    };
+    let options = SyncOptions {
+        events: match args.quiet {
+            true => None,
+            false => Some(Arc::new(print)),
+        },
+        workers: args.workers.unwrap_or(1),
+        delete_local: args.delete,
+        ..Default::default()
+    };
+    let remote_first = matches!(command, Command::Pull | Command::Clone);
+    let remote_optional = matches!(command, Command::Status | Command::Diff);
+    let (local, target) = paths(args, remote_first, remote_optional)?;
+    let remote = named_remote(&local, &target)?;
+    let (options, remote_path) = match &remote {
+        Some(remote) => (remote.options(&options), remote.dest_path()),
+        None => (options, target.path.as_path()),
+    };
+    let connect = || -> Result<SshCred, Failure> {
+        match &remote {
+            Some(remote) => Ok(remote.credentials()?),
+            None => Ok(credentials(args, &target)?),
+        }
+    };
+    match command {
+        Command::Push => {
+            let report = sync_with_options(&connect()?, &local, dest(remote_path), &options)?;
+            print_report(&report, "sent");
+        }
+        Command::Pull => {
+            let report = sync_pull_with_options(&connect()?, remote_path, &local, &options)?;
+            print_report(&report, "received");
+        }
+        Command::Clone => {
+            let ssh = connect()?;
+            let result = match args.file {
+                true => {
+                    let name = remote_path
+                        .file_name()
+                        .ok_or(format!("{:?} is not a file", remote_path))?;
+                    let dest = local.join(name);
+                    clone_file_with_options(&ssh, remote_path, &dest, Some(&local), &options)
+                }
+                false => clone_dir_with_options(&ssh, remote_path, &local, &options),
+            };
+            result?;
+        }
+        Command::Rebuild => {
+            let report = rebuild_with_options(&connect()?, &local, dest(remote_path), &options)?;
+            print_rebuild(&report);
+        }
+        Command::Status | Command::Diff => {
+            //the state of the server is used when it is known, only its address matters
+            let host = match &remote {
+                Some(remote) => Some((remote.host.clone(), remote.port.to_string())),
+                None => target
+                    .host
+                    .clone()
+                    .or_else(|| args.host.clone())
+                    .map(|host| (host, port(args))),
+            };
+            let plan = match host {
+                Some((host, port)) => {
+                    let ssh = SshCred::new(AuthOption::UserauthAgent(String::new()), host, port);
+                    dry_run_to_with_options(&ssh, &local, dest(remote_path), &options)?
+                }
+                None => dry_run_with_options(&local, dest(remote_path), &options)?,
+            };
+            match command {
+                Command::Status => print_status(&plan),
+                _ => print!("{}", plan),
+            }
+            if !plan.is_empty() {
+                return Ok(EXIT_CHANGES);
+            }
+        }
+    }
+    Ok(0)
+}
+
+fn main() -> ExitCode {
+    let mut args = env::args().skip(1);
+    let command = match args.next().as_deref() {
+        Some("-h" | "--help" | "help") => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Some(name) => parse_command(name),
+        None => Err("no command given".to_string()),
+    };
+    let parsed = command.and_then(|command| Ok((command, parse_args(args)?)));
+    let result = match parsed {
+        Ok((command, args)) => run(command, &args),
+        Err(message) => Err(Failure::Usage(message)),
+    };
+    match result {
+        Ok(code) => ExitCode::from(code),
+        Err(Failure::Sync(err)) => {
+            eprintln!("rxsync: {}", err);
+            ExitCode::from(exit_code(&err))
+        }
+        Err(Failure::Usage(message)) => {
+            eprintln!("rxsync: {}, see rxsync --help", message);
+            ExitCode::from(EXIT_USAGE)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Result<Args, String> {
+        parse_args(list.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(
+            Target::parse("deploy@example.com:/var/www"),
+            Target {
+                user: Some("deploy".to_string()),
+                host: Some("example.com".to_string()),
+                path: PathBuf::from("/var/www"),
+            }
+        );
+        let target = Target::parse("example.com:");
+        assert_eq!(target.host.as_deref(), Some("example.com"));
+        assert_eq!(target.user, None);
+        assert_eq!(dest(&target.path), None);
+        //a colon after a slash is part of a local path
+        assert_eq!(Target::parse("./a:b").host, None);
+        assert_eq!(Target::parse("www").path, PathBuf::from("www"));
+        assert_eq!(Target::parse(":www").host, None);
+    }
+
+    #[test]
+    fn test_parse_ipv6_target() {
+        assert_eq!(
+            Target::parse("[::1]:/srv"),
+            Target {
+                user: None,
+                host: Some("::1".to_string()),
+                path: PathBuf::from("/srv"),
+            }
+        );
+        assert_eq!(
+            Target::parse("user@[fe80::1]:path"),
+            Target {
+                user: Some("user".to_string()),
+                host: Some("fe80::1".to_string()),
+                path: PathBuf::from("path"),
+            }
+        );
+        //brackets in a local path or without a colon after them
+        assert_eq!(Target::parse("./[a]:b").host, None);
+        assert_eq!(Target::parse("[a]b").host, None);
+        assert_eq!(Target::parse("[]:www").host, None);
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let parsed = args(&["-p", "2222", "site/", "me@host:www", "-q", "-j", "4"]).unwrap();
+        assert_eq!(parsed.port.as_deref(), Some("2222"));
+        assert_eq!(parsed.workers, Some(4));
+        assert!(parsed.quiet);
+        assert_eq!(parsed.paths, vec!["site/", "me@host:www"]);
+        let (local, target) = paths(&parsed, false, false).unwrap();
+        assert_eq!(local, PathBuf::from("site/"));
+        assert_eq!(target.path, PathBuf::from("www"));
+
+        assert!(args(&["--port"]).is_err());
+        assert!(args(&["--workers", "0"]).is_err());
+        assert!(args(&["--force"]).is_err());
+        assert_eq!(args(&["--", "-dir"]).unwrap().paths, vec!["-dir"]);
+
+        let parsed = args(&["host:www", "copy"]).unwrap();
+        let (local, target) = paths(&parsed, true, false).unwrap();
+        assert_eq!(local, PathBuf::from("copy"));
+        assert_eq!(target.host.as_deref(), Some("host"));
+        assert!(paths(&args(&["site/"]).unwrap(), false, false).is_err());
+        assert!(paths(&args(&["site/"]).unwrap(), false, true).is_ok());
+    }
+
+    #[test]
+    fn test_credentials() {
+        let parsed = args(&["--host", "fallback", "--user", "me"]).unwrap();
+        assert!(credentials(&parsed, &Target::parse("www")).is_ok());
+        let parsed = Args::default();
+        assert!(credentials(&parsed, &Target::parse("www")).is_err());
+    }
 
-    match sync(&conn, Path::new("test_sync/"), Some(Path::new("elixir"))) {
-        Ok(_) => println!("okay"),
-        Err(e) => println!("{:?}", e),
+    #[test]
+    fn test_exit_code() {
+        let conflict = Error::Conflict {
+            paths: vec![PathBuf::from("site/index.html")],
+        };
+        assert_eq!(exit_code(&conflict), EXIT_CONFLICT);
+        let locked = Error::Locked {
+            path: PathBuf::from("site/.xsync/lock"),
+        };
+        assert_eq!(exit_code(&locked), EXIT_LOCAL);
+        let config = Error::Config {
+            path: PathBuf::from("site/.xsync/config.toml"),
+            message: "no remote named \"prod\"".to_string(),
+        };
+        assert_eq!(exit_code(&config), EXIT_LOCAL);
     }
 }
@@ -0,0 +1,124 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::events::EventHandler;
+
+///What a two-way sync does with a file changed both locally and on the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    ///send the local version to the server
+    LocalWins,
+    ///replace the local version with the one on the server
+    RemoteWins,
+    ///keep the local version and save the server one next to it with a `.remote` suffix,
+    ///both end up on each side
+    KeepBoth,
+    ///stop before changing anything and return the conflicting files
+    #[default]
+    Abort,
+}
+
+///What a sync or clone does with symbolic links
+///
+///Links leading outside the folder being synced are always skipped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    ///copy the file or folder the link points to
+    #[default]
+    Follow,
+    ///create the same link on the receiving side, pull and two-way sync skip links instead
+    Recreate,
+    ///leave links out
+    Skip,
+}
+
+///Hash recorded in `.xsync.toml` to tell whether a file changed since the last sync
+///
+///Entries written with another algorithm are still compared with the one they were written with,
+///and rewritten with this one when the file is read and found unchanged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum HashAlgorithm {
+    ///fast but collides easily, the only one older versions wrote
+    Adler32,
+    #[default]
+    Blake3,
+    Sha256,
+    ///64 bit XXH3, fast but not cryptographic
+    Xxh3,
+}
+
+///Metadata of the source kept on the files and folders a sync or clone writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Preserve {
+    ///permission bits, so executables stay executable
+    pub mode: bool,
+    ///modification and access times
+    pub times: bool,
+    ///owner and group ids, the receiving user needs the rights to change them
+    pub owner: bool,
+}
+
+impl Preserve {
+    pub fn any(&self) -> bool {
+        self.mode || self.times || self.owner
+    }
+}
+
+///Options to tune how a sync or clone runs
+#[derive(Clone)]
+pub struct SyncOptions {
+    ///number of ssh sessions files are uploaded over concurrently
+    pub workers: usize,
+    ///receives progress events, nothing is reported when unset
+    pub events: Option<Arc<dyn EventHandler>>,
+    ///when pulling, remove local files and folders that were removed from the server
+    pub delete_local: bool,
+    ///how a two-way sync resolves files changed on both sides
+    pub conflict: ConflictPolicy,
+    ///metadata copied along with file content, none by default
+    pub preserve: Preserve,
+    ///mode of the folders created on the server, the mode of the local folder when unset
+    pub dir_mode: Option<u32>,
+    ///how symbolic links are synced
+    pub symlinks: SymlinkPolicy,
+    ///hash recorded for the files written
+    pub hash: HashAlgorithm,
+    ///hash every file, even when its size and modification time match the last sync
+    pub checksum: bool,
+    ///more `.xsyncignore` lines for the synced folder, `!pattern` includes a file again
+    pub ignore: Vec<String>,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            workers: 1,
+            events: None,
+            delete_local: false,
+            conflict: ConflictPolicy::default(),
+            preserve: Preserve::default(),
+            dir_mode: None,
+            symlinks: SymlinkPolicy::default(),
+            hash: HashAlgorithm::default(),
+            checksum: false,
+            ignore: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Debug for SyncOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyncOptions")
+            .field("workers", &self.workers)
+            .field("events", &self.events.is_some())
+            .field("delete_local", &self.delete_local)
+            .field("conflict", &self.conflict)
+            .field("preserve", &self.preserve)
+            .field("dir_mode", &self.dir_mode)
+            .field("symlinks", &self.symlinks)
+            .field("hash", &self.hash)
+            .field("checksum", &self.checksum)
+            .field("ignore", &self.ignore)
+            .finish()
+    }
+}
@@ -0,0 +1,151 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::config::Stamp;
+
+///What a sync will do to a single item
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Action {
+    ///folder is missing on the receiving side and will be created
+    CreateFolder,
+    ///file is new and will be sent
+    CreateFile,
+    ///file changed since the last sync and will be sent again
+    UpdateFile,
+    ///file no longer exists on the sending side and will be removed
+    DeleteFile,
+    ///folder no longer exists on the sending side and will be removed with its content
+    DeleteFolder,
+}
+
+impl Action {
+    fn symbol(&self) -> &'static str {
+        match self {
+            Action::CreateFolder | Action::CreateFile => "+",
+            Action::UpdateFile => "~",
+            Action::DeleteFile | Action::DeleteFolder => "-",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlanItem {
+    pub action: Action,
+    ///local path of the item, as recorded in `.xsync.toml` when pushing
+    pub path: PathBuf,
+    ///path of the item on the server
+    pub remote: PathBuf,
+    ///size of the file to send, 0 for folders and deletes
+    pub size: u64,
+}
+
+///Every change a sync would make, computed without touching the server or `.xsync.toml`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncPlan {
+    pub items: Vec<PlanItem>,
+    ///files that match the last synced version and are skipped
+    pub unchanged: Vec<PathBuf>,
+    ///folders already on the server, only their mode is checked
+    pub synced_folders: Vec<PathBuf>,
+    ///symbolic links left out by the policy or leading outside the synced folder
+    pub skipped_links: Vec<PathBuf>,
+    //unchanged files whose `.xsync.toml` entry is rewritten, to move it to the configured
+    //hash or refresh the stamp of the local copy, keyed like the entry
+    pub(crate) refreshed: Vec<(PathBuf, String, Option<Stamp>)>,
+}
+
+impl SyncPlan {
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn with_action(&self, action: Action) -> impl Iterator<Item = &PlanItem> {
+        self.items.iter().filter(move |item| item.action == action)
+    }
+
+    ///bytes of file content the sync would upload at most
+    pub fn upload_size(&self) -> u64 {
+        self.items
+            .iter()
+            .filter(|item| matches!(item.action, Action::CreateFile | Action::UpdateFile))
+            .map(|item| item.size)
+            .sum()
+    }
+
+    pub(crate) fn push(&mut self, action: Action, path: PathBuf, remote: PathBuf, size: u64) {
+        self.items.push(PlanItem {
+            action,
+            path,
+            remote,
+            size,
+        })
+    }
+}
+
+//one line per item sorted by path so two plans can be diffed
+impl fmt::Display for SyncPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut items: Vec<&PlanItem> = self.items.iter().collect();
+        items.sort_by(|a, b| a.path.cmp(&b.path).then(a.action.cmp(&b.action)));
+        for item in items {
+            match item.action {
+                Action::CreateFile | Action::UpdateFile => writeln!(
+                    f,
+                    "{} {} ({} bytes)",
+                    item.action.symbol(),
+                    item.remote.display(),
+                    item.size
+                )?,
+                Action::CreateFolder | Action::DeleteFolder => {
+                    //folder paths of the plan may already end with a slash
+                    let remote = item.remote.components().as_path();
+                    writeln!(f, "{} {}/", item.action.symbol(), remote.display())?
+                }
+                Action::DeleteFile => {
+                    writeln!(f, "{} {}", item.action.symbol(), item.remote.display())?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_display_and_sizes() {
+        let mut plan = SyncPlan::default();
+        plan.push(
+            Action::UpdateFile,
+            PathBuf::from("b/file"),
+            PathBuf::from("dest/b/file"),
+            10,
+        );
+        plan.push(
+            Action::CreateFolder,
+            PathBuf::from("b"),
+            PathBuf::from("dest/b"),
+            0,
+        );
+        plan.push(
+            Action::CreateFile,
+            PathBuf::from("a"),
+            PathBuf::from("dest/a"),
+            5,
+        );
+        plan.push(
+            Action::DeleteFile,
+            PathBuf::from("c"),
+            PathBuf::from("dest/c"),
+            0,
+        );
+        assert_eq!(plan.upload_size(), 15);
+        assert_eq!(plan.with_action(Action::CreateFile).count(), 1);
+        assert_eq!(
+            plan.to_string(),
+            "+ dest/a (5 bytes)\n+ dest/b/\n~ dest/b/file (10 bytes)\n- dest/c\n"
+        );
+    }
+}
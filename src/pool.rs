@@ -0,0 +1,79 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use crate::connection::SshCred;
+use crate::error::Error;
+use crate::sftp::SftpSync;
+
+///a set of sftp sessions opened from the same credentials
+pub struct SftpPool {
+    sessions: Vec<SftpSync>,
+}
+
+impl SftpPool {
+    pub fn connect(ssh: &SshCred, size: usize) -> Result<Self, Error> {
+        let mut sessions = vec![];
+        for _ in 0..size.max(1) {
+            sessions.push(SftpSync::connect(ssh)?);
+        }
+        Ok(SftpPool { sessions })
+    }
+
+    ///session used for work that has to happen in order
+    pub fn primary(&self) -> &SftpSync {
+        &self.sessions[0]
+    }
+
+    //spread jobs across one worker thread per session
+    //results are handed to on_done on the calling thread as they come in,
+    //so anything it touches doesn't need to be shared between workers.
+    //the first error stops the remaining jobs and is returned
+    pub fn run<T, U, F, D>(&self, mut jobs: Vec<T>, work: F, mut on_done: D) -> Result<(), Error>
+    where
+        T: Send,
+        U: Send,
+        F: Fn(&SftpSync, &T) -> Result<U, Error> + Sync,
+        D: FnMut(T, U) -> Result<(), Error>,
+    {
+        //jobs are popped off the back, keep them in the order given
+        jobs.reverse();
+        let queue = Mutex::new(jobs);
+        let stop = AtomicBool::new(false);
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            for session in &self.sessions {
+                let sender = sender.clone();
+                let (queue, stop, work) = (&queue, &stop, &work);
+                scope.spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        let job = match queue.lock().unwrap().pop() {
+                            Some(job) => job,
+                            None => break,
+                        };
+                        let result = work(session, &job);
+                        if sender.send((job, result)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(sender);
+
+            let mut first_error = None;
+            for (job, result) in receiver {
+                if first_error.is_some() {
+                    continue;
+                }
+                if let Err(err) = result.and_then(|value| on_done(job, value)) {
+                    stop.store(true, Ordering::Relaxed);
+                    first_error = Some(err);
+                }
+            }
+            match first_error {
+                Some(err) => Err(err),
+                None => Ok(()),
+            }
+        })
+    }
+}
@@ -0,0 +1,294 @@
+use ssh2::FileStat;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::*;
+use crate::error::{Error, PathContext};
+use crate::events::{Events, SyncEvent};
+use crate::file_util::{apply_stat, compare_recorded, local_stamp, Recorded};
+use crate::options::SyncOptions;
+use crate::plan::{Action, PlanItem, SyncPlan};
+use crate::pool::SftpPool;
+use crate::report::SyncReport;
+use crate::transfer::{remote_stamp, Transfers};
+
+//local path a remote item is downloaded to, remote paths are kept under dest
+//the same way clone_dir lays them out
+pub fn local_path(dest: &Path, remote: &Path) -> PathBuf {
+    dest.join(remote.strip_prefix("/").unwrap_or(remote))
+}
+
+fn modified_secs(metadata: &fs::Metadata) -> Option<u64> {
+    let modified = metadata.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+//compare the remote tree under src with the local copy in dest and its toml config
+//a file is downloaded when it is missing locally, its size or modification time
+//differs from the server, or its content no longer matches the config
+pub fn plan_pull(
+    tree: &[(PathBuf, FileStat)],
+    src: &Path,
+    dest: &Path,
+    parsed_config: &Config,
+    options: &SyncOptions,
+) -> Result<SyncPlan, Error> {
+    let mut plan = SyncPlan::default();
+    for (remote, stat) in tree {
+        let local = local_path(dest, remote);
+        let key = remote.to_str().unwrap();
+        if stat.is_dir() {
+            if !local.is_dir() || !parsed_config.folders.contains_key(key) {
+                plan.push(Action::CreateFolder, local, remote.clone(), 0);
+            }
+            continue;
+        }
+        let size = stat.size.unwrap_or(0);
+        let action = match fs::metadata(&local) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Action::CreateFile,
+            Err(err) => return Err(err).at(&local),
+            Ok(metadata) => {
+                let same_stat = metadata.len() == size
+                    && (stat.mtime.is_none() || stat.mtime == modified_secs(&metadata));
+                let recorded = match same_stat {
+                    true => compare_recorded(&local, key, parsed_config, options)?,
+                    false => Recorded::Changed,
+                };
+                match recorded {
+                    Recorded::Changed => Action::UpdateFile,
+                    Recorded::Unchanged => {
+                        plan.unchanged.push(local);
+                        continue;
+                    }
+                    Recorded::Refresh(checksum, stamp) => {
+                        plan.refreshed.push((remote.clone(), checksum, stamp));
+                        plan.unchanged.push(local);
+                        continue;
+                    }
+                }
+            }
+        };
+        plan.push(action, local, remote.clone(), size);
+    }
+
+    if options.delete_local {
+        let on_server: HashSet<&Path> = tree.iter().map(|(path, _)| path.as_path()).collect();
+        let removed = |items: Vec<&String>| -> Vec<PathBuf> {
+            items
+                .into_iter()
+                .map(PathBuf::from)
+                .filter(|path| path.starts_with(src) && !on_server.contains(path.as_path()))
+                .collect()
+        };
+        for remote in removed(parsed_config.files.keys().collect()) {
+            plan.push(Action::DeleteFile, local_path(dest, &remote), remote, 0);
+        }
+        for remote in removed(parsed_config.folders.keys().collect()) {
+            plan.push(Action::DeleteFolder, local_path(dest, &remote), remote, 0);
+        }
+    }
+    Ok(plan)
+}
+
+fn record(
+    key_config: &str,
+    item: &PlanItem,
+    manifest: Manifest,
+    value: Option<String>,
+) -> Result<(), Error> {
+    let key = item.remote.to_str().unwrap().to_string();
+    let action = match value {
+        Some(value) => FolderConfig::Add(key, value),
+        None => FolderConfig::Remove(key),
+    };
+    update_folder_config(key_config, manifest, &action)
+}
+
+//already gone is as good as removed
+pub fn ignore_not_found(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+pub fn apply_pull(
+    plan: &SyncPlan,
+    tree: &[(PathBuf, FileStat)],
+    manifest: Manifest,
+    pool: &SftpPool,
+    events: Events,
+    options: &SyncOptions,
+) -> Result<SyncReport, Error> {
+    let preserve = options.preserve;
+    let mut report = SyncReport {
+        files_unchanged: plan.unchanged.clone(),
+        ..SyncReport::default()
+    };
+    for i in &plan.unchanged {
+        events.emit(SyncEvent::FileSkipped { path: i });
+    }
+    for (key, checksum, stamp) in &plan.refreshed {
+        update_folder_config(
+            "files",
+            manifest,
+            &FolderConfig::AddFile(key.to_str().unwrap().to_string(), checksum.clone(), *stamp),
+        )?;
+    }
+
+    let mut create_folder: Vec<&PlanItem> = plan.with_action(Action::CreateFolder).collect();
+    create_folder.sort_by_key(|i| i.path.components().count());
+    for i in create_folder {
+        fs::create_dir_all(&i.path).at(&i.path)?;
+        record("folders", i, manifest, Some("".to_string()))?;
+        events.emit(SyncEvent::FolderCreated { path: &i.path });
+        report.folders_created.push(i.path.clone());
+    }
+
+    for i in plan.with_action(Action::DeleteFile) {
+        ignore_not_found(fs::remove_file(&i.path)).at(&i.path)?;
+        record("files", i, manifest, None)?;
+        events.emit(SyncEvent::Deleted { path: &i.path });
+        report.files_deleted.push(i.path.clone());
+    }
+
+    let mut delete_folder: Vec<&PlanItem> = plan.with_action(Action::DeleteFolder).collect();
+    delete_folder.sort_by_key(|i| std::cmp::Reverse(i.path.components().count()));
+    for i in delete_folder {
+        ignore_not_found(fs::remove_dir_all(&i.path)).at(&i.path)?;
+        record("folders", i, manifest, None)?;
+        events.emit(SyncEvent::Deleted { path: &i.path });
+        report.folders_deleted.push(i.path.clone());
+    }
+
+    //downloaded files get the modification time they have on the server
+    //so the next pull can tell they are up to date without reading them
+    let mtimes: HashMap<&Path, u64> = tree
+        .iter()
+        .filter_map(|(path, stat)| Some((path.as_path(), stat.mtime?)))
+        .collect();
+    let receive_list: Vec<&PlanItem> = plan
+        .items
+        .iter()
+        .filter(|i| matches!(i.action, Action::CreateFile | Action::UpdateFile))
+        .collect();
+    //downloads interrupted by an earlier pull resume when the server copy didn't move
+    let stamps: HashMap<&Path, Option<Stamp>> = tree
+        .iter()
+        .map(|(path, stat)| (path.as_path(), remote_stamp(stat)))
+        .collect();
+    let started: Vec<(PathBuf, Option<Stamp>)> = receive_list
+        .iter()
+        .map(|i| (i.path.clone(), stamps[i.remote.as_path()]))
+        .collect();
+    let mut transfers = Transfers::load(manifest)?;
+    let resume = transfers.start_downloads(&started);
+    transfers.save(manifest)?;
+    pool.run(
+        receive_list,
+        |sftp_conn, i| {
+            if let Some(parent) = i.path.parent() {
+                fs::create_dir_all(parent).at(parent)?;
+            }
+            let resume = resume.contains(&i.path);
+            let received = sftp_conn.receive_file(&i.remote, &i.path, resume, events, options)?;
+            if let Some(mtime) = mtimes.get(i.remote.as_path()) {
+                let file = fs::File::options().write(true).open(&i.path).at(&i.path)?;
+                file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(*mtime))
+                    .at(&i.path)?;
+            }
+            Ok((received, local_stamp(&i.path)?))
+        },
+        |i, ((checksum, bytes), stamp)| {
+            let key = i.remote.to_str().unwrap().to_string();
+            let action = FolderConfig::AddFile(key, checksum, stamp);
+            update_folder_config("files", manifest, &action)?;
+            report.bytes_transferred += bytes;
+            if i.action == Action::UpdateFile {
+                report.files_updated.push(i.path.clone());
+            } else {
+                report.files_created.push(i.path.clone());
+            }
+            Ok(())
+        },
+    )?;
+    transfers.finish_downloads();
+    transfers.save(manifest)?;
+
+    //folder metadata is set once their content is written
+    if preserve.any() {
+        let stats: HashMap<&Path, &FileStat> = tree
+            .iter()
+            .map(|(path, stat)| (path.as_path(), stat))
+            .collect();
+        for i in plan.with_action(Action::CreateFolder) {
+            apply_stat(&i.path, stats[i.remote.as_path()], preserve)?;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat(size: Option<u64>, dir: bool) -> FileStat {
+        FileStat {
+            size,
+            uid: None,
+            gid: None,
+            perm: Some(if dir { 0o040755 } else { 0o100644 }),
+            atime: None,
+            mtime: None,
+        }
+    }
+
+    #[test]
+    fn test_plan_pull() {
+        let dest = Path::new("test_pull");
+        fs::create_dir_all(dest.join("remote")).unwrap();
+        fs::write(dest.join("remote/same"), b"same").unwrap();
+        fs::write(dest.join("remote/edited"), b"edited locally").unwrap();
+        let checksum = format!("{}", adler::adler32_slice(b"same"));
+        let parsed_config = Config {
+            folders: HashMap::from([("remote".to_string(), FolderEntry::default())]),
+            files: HashMap::from([
+                ("remote/same".to_string(), FileEntry::new(&checksum)),
+                ("remote/edited".to_string(), FileEntry::new(&checksum)),
+                ("remote/gone".to_string(), FileEntry::new(&checksum)),
+                ("elsewhere/file".to_string(), FileEntry::new(&checksum)),
+            ]),
+            ..Default::default()
+        };
+        let tree = vec![
+            (PathBuf::from("remote"), stat(None, true)),
+            (PathBuf::from("remote/same"), stat(Some(4), false)),
+            (PathBuf::from("remote/edited"), stat(Some(14), false)),
+            (PathBuf::from("remote/new"), stat(Some(3), false)),
+        ];
+
+        let mut options = SyncOptions {
+            delete_local: true,
+            ..Default::default()
+        };
+        let plan = plan_pull(&tree, Path::new("remote"), dest, &parsed_config, &options).unwrap();
+        fs::remove_dir_all(dest).unwrap();
+        assert_eq!(plan.unchanged, vec![dest.join("remote/same")]);
+        //the adler-32 entry moves to the configured hash without a download
+        let same = format!("blake3:{}", blake3::hash(b"same").to_hex());
+        assert_eq!(plan.refreshed.len(), 1);
+        assert_eq!(plan.refreshed[0].0, PathBuf::from("remote/same"));
+        assert_eq!(plan.refreshed[0].1, same);
+        assert_eq!(
+            plan.to_string(),
+            "~ remote/edited (14 bytes)\n- remote/gone\n+ remote/new (3 bytes)\n"
+        );
+        options.delete_local = false;
+        let plan = plan_pull(&tree, Path::new("remote"), dest, &parsed_config, &options).unwrap();
+        assert_eq!(plan.with_action(Action::DeleteFile).count(), 0);
+        assert_eq!(plan.with_action(Action::CreateFolder).count(), 1);
+    }
+}
@@ -0,0 +1,189 @@
+use ssh2::FileStat;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::*;
+use crate::connection::SshCred;
+use crate::delta;
+use crate::error::{Error, PathContext};
+use crate::events::Events;
+use crate::file_util::{
+    checksum_file, copy_chunked, local_link_target, local_stamp, ChecksumReader,
+};
+use crate::options::{HashAlgorithm, SymlinkPolicy, SyncOptions};
+use crate::plan::{Action, PlanItem};
+use crate::pull::ignore_not_found;
+use crate::report::RebuildReport;
+use crate::sftp::SftpSync;
+
+//every item under root on the server by remote path, nothing when root doesn't exist
+fn remote_items(
+    sftp_conn: &SftpSync,
+    root: &Path,
+    symlinks: SymlinkPolicy,
+) -> Result<HashMap<PathBuf, FileStat>, Error> {
+    match sftp_conn.list_all(root, symlinks, Events(None)) {
+        Ok(items) => Ok(items.into_iter().collect()),
+        Err(err) if err.sftp_code() == Some(2) => Ok(HashMap::new()),
+        Err(err) => Err(err),
+    }
+}
+
+//local files whose copy on the server has the same content
+//they are hashed on the server when it has a command for it, read through otherwise
+fn same_content(
+    sftp_conn: &SftpSync,
+    items: &[&PlanItem],
+    hash: HashAlgorithm,
+) -> Result<HashSet<PathBuf>, Error> {
+    let remote: Vec<PathBuf> = items.iter().map(|i| i.remote.clone()).collect();
+    let mut algorithms = vec![hash];
+    for other in [HashAlgorithm::Blake3, HashAlgorithm::Sha256] {
+        if !algorithms.contains(&other) {
+            algorithms.push(other);
+        }
+    }
+    let mut hashes = None;
+    for algorithm in algorithms {
+        if let Some(found) = sftp_conn.remote_hashes(&remote, algorithm)? {
+            hashes = Some((algorithm, found));
+            break;
+        }
+    }
+    let mut same = HashSet::new();
+    for i in items {
+        let matches = match &hashes {
+            Some((algorithm, found)) if found.contains_key(&i.remote) => {
+                found[&i.remote] == checksum_file(&i.path, *algorithm)?
+            }
+            _ => sftp_conn.checksum_file(&i.remote, hash)? == checksum_file(&i.path, hash)?,
+        };
+        if matches {
+            same.insert(i.path.clone());
+        }
+    }
+    Ok(same)
+}
+
+//record a local file found on the server as if it had just been uploaded
+fn record_file(path: &Path, manifest: Manifest, options: &SyncOptions) -> Result<(), Error> {
+    let stamp = local_stamp(path)?;
+    let file = fs::File::open(path).at(path)?;
+    let size = file.metadata().at(path)?.len();
+    let mut reader =
+        ChecksumReader::with_signature(file, options.hash, delta::block_size_for(size));
+    copy_chunked(&mut reader, &mut io::sink()).at(path)?;
+    let checksum = reader.checksum();
+    if let Some(signature) = reader.into_signature() {
+        delta::write_signature(manifest, path, &signature)?;
+    }
+    let key = path.to_str().unwrap().to_string();
+    update_folder_config(
+        "files",
+        manifest,
+        &FolderConfig::AddFile(key, checksum, stamp),
+    )
+}
+
+pub fn run_rebuild(
+    ssh: &SshCred,
+    src: &Path,
+    dest_path: &Path,
+    options: &SyncOptions,
+) -> Result<RebuildReport, Error> {
+    let config_dir = crate::config_dir(src)?;
+    let (config, moved_aside) = OpenConfig::open_or_reset(config_dir)?;
+    let target = target_key(ssh, dest_path);
+    let manifest = config.remote(&target);
+    //the state found on the server replaces whatever was recorded for it
+    config.reset(Some(&target))?;
+    let signatures = manifest.state_path(delta::SIGNATURES_DIR);
+    ignore_not_found(fs::remove_dir_all(&signatures)).at(&signatures)?;
+    //with nothing recorded every local item is planned for upload
+    let (_, plan) = crate::plan_sync(src, dest_path, Some(&target), Some(&config), options)?;
+
+    let sftp_conn = SftpSync::connect(ssh)?;
+    let root = dest_path.join(src);
+    let remote = remote_items(&sftp_conn, &root, options.symlinks)?;
+    //parents of src are outside of the listed folder
+    let stat = |path: &Path| -> Result<Option<FileStat>, Error> {
+        match remote.get(path) {
+            Some(stat) => Ok(Some(stat.clone())),
+            None if path.starts_with(&root) => Ok(None),
+            None => match sftp_conn.sftp.stat(path) {
+                Ok(stat) => Ok(Some(stat)),
+                Err(err) if err.code() == ssh2::ErrorCode::SFTP(2) => Ok(None),
+                Err(err) => Err(err).at(path),
+            },
+        }
+    };
+
+    let mut report = RebuildReport {
+        moved_aside,
+        ..RebuildReport::default()
+    };
+    let link_root = fs::canonicalize(config_dir).at(config_dir)?;
+    let mut to_hash = vec![];
+    for i in &plan.items {
+        let key = i.path.to_str().unwrap().to_string();
+        let is_link = options.symlinks == SymlinkPolicy::Recreate
+            && fs::symlink_metadata(&i.path).at(&i.path)?.is_symlink();
+        let matched = match i.action {
+            Action::CreateFolder => match stat(&i.remote)? {
+                Some(stat) if stat.is_dir() => {
+                    update_folder_config("folders", manifest, &FolderConfig::Add(key, "".into()))?;
+                    true
+                }
+                _ => false,
+            },
+            Action::CreateFile if is_link => {
+                let target = local_link_target(&i.path, &link_root)?;
+                let found = sftp_conn.sftp.readlink(&i.remote).ok();
+                match target {
+                    Some(target) if found.as_ref() == Some(&target) => {
+                        let action = FolderConfig::AddFile(key, crate::link_value(&target), None);
+                        update_folder_config("files", manifest, &action)?;
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            Action::CreateFile => match stat(&i.remote)? {
+                Some(stat) if stat.is_file() && stat.size == Some(i.size) => {
+                    to_hash.push(i);
+                    continue;
+                }
+                _ => false,
+            },
+            Action::UpdateFile | Action::DeleteFile | Action::DeleteFolder => continue,
+        };
+        match matched {
+            true => report.matched.push(i.path.clone()),
+            false => report.differing.push(i.path.clone()),
+        }
+    }
+
+    let same = same_content(&sftp_conn, &to_hash, options.hash)?;
+    for i in to_hash {
+        match same.contains(&i.path) {
+            true => {
+                record_file(&i.path, manifest, options)?;
+                report.matched.push(i.path.clone());
+            }
+            false => report.differing.push(i.path.clone()),
+        }
+    }
+
+    let planned: HashSet<&Path> = plan.items.iter().map(|i| i.remote.as_path()).collect();
+    report.remote_only = remote
+        .into_keys()
+        .filter(|path| !planned.contains(path.as_path()))
+        .collect();
+    report.matched.sort();
+    report.differing.sort();
+    report.remote_only.sort();
+    config.close()?;
+    Ok(report)
+}
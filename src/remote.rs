@@ -0,0 +1,230 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::STATE_DIR;
+use crate::connection::{AuthOption, SshCred};
+use crate::error::{Error, PathContext};
+use crate::options::SyncOptions;
+
+///File in the `.xsync` folder naming the remotes a folder syncs to
+pub const REMOTES_FILE: &str = "config.toml";
+
+///How to log in to a remote
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(tag = "method", rename_all = "lowercase", deny_unknown_fields)]
+pub enum RemoteAuth {
+    ///the first key of the ssh agent that the server accepts
+    #[default]
+    Agent,
+    ///a private key file, a leading `~/` is the home folder
+    Key {
+        key: PathBuf,
+        public_key: Option<PathBuf>,
+        ///environment variable holding the passphrase of the key
+        passphrase_env: Option<String>,
+    },
+    ///a password read from an environment variable so it stays out of the file
+    Password { password_env: String },
+}
+
+///A server and folder to sync to, named in `.xsync/config.toml`
+///
+///```toml
+///[remote.production]
+///host = "example.com"
+///port = 22
+///user = "deploy"
+///auth = { method = "key", key = "~/.ssh/id_ed25519" }
+///dest = "www"
+///ignore = ["*.log"]
+///include = ["logs/keep.log"]
+///```
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Remote {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub user: String,
+    #[serde(default)]
+    pub auth: RemoteAuth,
+    ///folder on the server, the login folder when unset
+    pub dest: Option<PathBuf>,
+    ///`.xsyncignore` lines only applying to this remote
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    ///patterns synced even when an ignore rule matches them
+    #[serde(default)]
+    pub include: Vec<String>,
+    //the file the remote was read from, for errors
+    #[serde(skip)]
+    source: PathBuf,
+}
+
+fn default_port() -> u16 {
+    22
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RemotesFile {
+    #[serde(default)]
+    remote: BTreeMap<String, Remote>,
+}
+
+pub fn remotes_file_path(root: &Path) -> PathBuf {
+    root.join(STATE_DIR).join(REMOTES_FILE)
+}
+
+///Every remote named in the `.xsync/config.toml` file of root
+pub fn load_remotes(root: &Path) -> Result<BTreeMap<String, Remote>, Error> {
+    let path = remotes_file_path(root);
+    let data = fs::read_to_string(&path).at(&path)?;
+    let file: RemotesFile = toml::from_str(&data).map_err(|err| Error::Config {
+        path: path.clone(),
+        message: err.to_string(),
+    })?;
+    Ok(file
+        .remote
+        .into_iter()
+        .map(|(name, remote)| {
+            let source = path.clone();
+            (name, Remote { source, ..remote })
+        })
+        .collect())
+}
+
+///The remote called name in the `.xsync/config.toml` file of root
+pub fn find_remote(root: &Path, name: &str) -> Result<Remote, Error> {
+    load_remotes(root)?
+        .remove(name)
+        .ok_or_else(|| Error::Config {
+            path: remotes_file_path(root),
+            message: format!("no remote named {:?}", name),
+        })
+}
+
+//a leading `~/` is the home folder
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+impl Remote {
+    fn env_var(&self, name: &str) -> Result<String, Error> {
+        env::var(name).map_err(|_| Error::Config {
+            path: self.source.clone(),
+            message: format!("environment variable {} is not set", name),
+        })
+    }
+
+    ///Credentials to connect to the remote, secrets are read from the environment now
+    pub fn credentials(&self) -> Result<SshCred, Error> {
+        let user = self.user.clone();
+        let auth = match &self.auth {
+            RemoteAuth::Agent => AuthOption::UserauthAgent(user),
+            RemoteAuth::Key {
+                key,
+                public_key,
+                passphrase_env,
+            } => {
+                let passphrase = match passphrase_env {
+                    Some(name) => Some(self.env_var(name)?),
+                    None => None,
+                };
+                let public_key = public_key.as_deref().map(expand_home);
+                AuthOption::UserauthPubkeyFile(user, public_key, expand_home(key), passphrase)
+            }
+            RemoteAuth::Password { password_env } => {
+                AuthOption::UserauthPassword(user, self.env_var(password_env)?)
+            }
+        };
+        Ok(SshCred::new(auth, self.host.clone(), self.port.to_string()))
+    }
+
+    ///options with the ignore and include rules of the remote added to the ones set
+    pub fn options(&self, options: &SyncOptions) -> SyncOptions {
+        let mut options = options.clone();
+        options.ignore.extend(self.ignore.iter().cloned());
+        options
+            .ignore
+            .extend(self.include.iter().map(|pattern| format!("!{}", pattern)));
+        options
+    }
+
+    ///folder on the server to sync to
+    pub fn dest_path(&self) -> &Path {
+        self.dest.as_deref().unwrap_or_else(|| Path::new(""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_remotes() {
+        let root = Path::new("test_remotes");
+        fs::create_dir_all(root.join(STATE_DIR)).unwrap();
+        fs::write(
+            remotes_file_path(root),
+            r#"
+[remote.staging]
+host = "staging.example.com"
+user = "deploy"
+
+[remote.production]
+host = "example.com"
+port = 2222
+user = "deploy"
+auth = { method = "password", password_env = "RXSYNC_TEST_UNSET" }
+dest = "www"
+ignore = ["*.log"]
+include = ["keep.log"]
+"#,
+        )
+        .unwrap();
+        let remotes = load_remotes(root);
+        let missing = find_remote(root, "dev");
+        fs::write(remotes_file_path(root), "[remote.typo]\nhots = \"x\"\n").unwrap();
+        let invalid = load_remotes(root);
+        fs::remove_dir_all(root).unwrap();
+
+        let remotes = remotes.unwrap();
+        assert_eq!(remotes.len(), 2);
+        let staging = &remotes["staging"];
+        assert_eq!(staging.port, 22);
+        assert_eq!(staging.auth, RemoteAuth::Agent);
+        assert_eq!(staging.dest_path(), Path::new(""));
+        assert!(staging.credentials().is_ok());
+
+        let production = &remotes["production"];
+        assert_eq!(production.port, 2222);
+        assert_eq!(production.dest_path(), Path::new("www"));
+        let options = production.options(&SyncOptions::default());
+        assert_eq!(options.ignore, vec!["*.log", "!keep.log"]);
+        match production.credentials() {
+            Err(Error::Config { message, .. }) => assert!(message.contains("RXSYNC_TEST_UNSET")),
+            _ => panic!("expected the password variable to be missing"),
+        }
+
+        assert!(matches!(missing, Err(Error::Config { .. })));
+        assert!(matches!(invalid, Err(Error::Config { .. })));
+    }
+
+    #[test]
+    fn test_expand_home() {
+        let home = env::var_os("HOME").map(PathBuf::from);
+        let expanded = expand_home(Path::new("~/.ssh/id_ed25519"));
+        match home {
+            Some(home) => assert_eq!(expanded, home.join(".ssh/id_ed25519")),
+            None => assert_eq!(expanded, Path::new("~/.ssh/id_ed25519")),
+        }
+        assert_eq!(expand_home(Path::new("keys/id")), Path::new("keys/id"));
+    }
+}
@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+
+///Summary of what a sync did, paths are the local paths recorded in `.xsync.toml`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    pub files_created: Vec<PathBuf>,
+    pub files_updated: Vec<PathBuf>,
+    pub files_deleted: Vec<PathBuf>,
+    ///files skipped because they match the last synced version
+    pub files_unchanged: Vec<PathBuf>,
+    pub folders_created: Vec<PathBuf>,
+    pub folders_deleted: Vec<PathBuf>,
+    ///file content sent to the server, only changed blocks count for delta updates
+    pub bytes_transferred: u64,
+}
+
+impl SyncReport {
+    ///true when the sync didn't change anything on the server
+    pub fn is_noop(&self) -> bool {
+        self.files_created.is_empty()
+            && self.files_updated.is_empty()
+            && self.files_deleted.is_empty()
+            && self.folders_created.is_empty()
+            && self.folders_deleted.is_empty()
+    }
+}
+
+///What a verify found on the server compared with `.xsync.toml`, and the uploads repairing it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DriftReport {
+    ///files and folders recorded as synced but gone from the server
+    pub missing: Vec<PathBuf>,
+    ///files and links whose size, hash or target on the server isn't the recorded one
+    pub modified: Vec<PathBuf>,
+    ///files only compared by size, the server has no command for their hash
+    pub size_only: Vec<PathBuf>,
+    ///the sync sending the missing and modified items again
+    pub repaired: SyncReport,
+}
+
+impl DriftReport {
+    ///true when the server matched `.xsync.toml`
+    pub fn no_drift(&self) -> bool {
+        self.missing.is_empty() && self.modified.is_empty()
+    }
+}
+
+///Summary of a two-way sync
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TwoWayReport {
+    ///changes sent to the server
+    pub pushed: SyncReport,
+    ///changes brought from the server into the local folder
+    pub pulled: SyncReport,
+    ///files changed on both sides, resolved with the conflict policy
+    pub conflicts: Vec<PathBuf>,
+}
+
+///What a rebuild of `.xsync.toml` found on the server
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RebuildReport {
+    ///local files and folders whose copy on the server matches, recorded as synced
+    pub matched: Vec<PathBuf>,
+    ///local files and folders missing or different on the server, sent by the next sync
+    pub differing: Vec<PathBuf>,
+    ///remote files and folders with no local counterpart, left as they are
+    pub remote_only: Vec<PathBuf>,
+    ///where a `.xsync.toml` that couldn't be parsed was moved
+    pub moved_aside: Option<PathBuf>,
+}
@@ -1,101 +1,620 @@
-use ssh2::{ErrorCode, Session, Sftp};
+use ssh2::{Channel, ErrorCode, FileStat, OpenFlags, OpenType, RenameFlags, Session, Sftp};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::Error;
-use std::path::Path;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 use std::io::prelude::*;
 
 use crate::config::*;
-use crate::file_util::create_checksum;
+use crate::connection::SshCred;
+use crate::delta::{Delta, Moved, Signature};
+use crate::error::{Error, PathContext};
+use crate::events::{Events, ProgressReader, SyncEvent};
+use crate::file_util::{
+    apply_stat, contained_link_target, copy_after, copy_chunked, create_local_link, is_state_path,
+    local_stamp, local_stat, prefix_checksum, ChecksumReader,
+};
+use crate::options::{HashAlgorithm, Preserve, SymlinkPolicy, SyncOptions};
+use crate::transfer::{remote_stamp, Transfers};
+
+//moved blocks copied by a single remote command
+const COPY_BATCH: usize = 100;
+
+//files hashed by a single remote command
+const HASH_BATCH: usize = 200;
+
+//looks for the commands of RemoteTools, one line naming each found
+const TOOLS_PROBE: &str = "command -v b3sum >/dev/null 2>&1 && echo b3sum; \
+    dd if=/dev/null of=/dev/null count=0 iflag=skip_bytes,count_bytes oflag=seek_bytes \
+    2>/dev/null && echo dd; \
+    if t=$(mktemp 2>/dev/null); then \
+    cp -p -- \"$t\" \"$t.p\" 2>/dev/null && echo cp; rm -f -- \"$t\" \"$t.p\"; fi";
+
+//commands the block level upload runs on the server, looked up once per session
+#[derive(Clone, Copy)]
+struct RemoteTools {
+    //checks the remote copy is still the last upload
+    b3sum: bool,
+    //dd that copies by byte offset, moves blocks within the file
+    dd_bytes: bool,
+    //cp -p, copies the remote file with its mode to rebuild it aside
+    cp_preserve: bool,
+}
+
+//exit status of a prefix check whose file can't be read on the server
+const UNREADABLE_STATUS: i32 = 3;
+
+//why an interrupted transfer starts over
+const NO_PREFIX_HASH: &str = "no b3sum or sha256sum on the server to check the partial copy, \
+                              starting over";
+
+//quote a path for a posix shell
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.to_str().unwrap().replace('\'', "'\\''"))
+}
+
+///mode of folders created on the server with no local folder to copy it from
+pub const DEFAULT_DIR_MODE: u32 = 0o755;
+
+//state carried through the folders of a listing
+struct Listing<'a> {
+    follow: bool,
+    events: Events<'a>,
+    //real path of the listed folder on the server
+    root: PathBuf,
+    //real paths of the folders already followed into
+    visited: HashSet<PathBuf>,
+}
+
+//state carried through the folders of a clone
+struct Clone<'a> {
+    dest: &'a Path,
+    manifest: Manifest<'a>,
+    events: Events<'a>,
+    options: &'a SyncOptions,
+    //real path of the cloned folder on the server
+    root: PathBuf,
+    //real paths of the folders already cloned
+    visited: HashSet<PathBuf>,
+}
 
 pub struct SftpSync {
     pub sftp: Sftp,
     pub sess: Session,
+    tools: OnceLock<RemoteTools>,
+}
+
+//errors while data streams over an open remote file or channel
+fn transfer_error(path: &Path) -> impl FnOnce(io::Error) -> Error + '_ {
+    move |source| Error::Transfer {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+//close a scp channel and wait for the server to be done with it
+fn close_channel(channel: &mut Channel, path: &Path) -> Result<(), Error> {
+    channel.send_eof().at(path)?;
+    channel.wait_eof().at(path)?;
+    channel.close().at(path)?;
+    channel.wait_close().at(path)
 }
 
 impl SftpSync {
-    pub fn new(sess: Session) -> Result<Self, Error> {
+    pub fn new(sess: Session) -> Result<Self, ssh2::Error> {
         let sftp = sess.sftp()?;
-        Ok(SftpSync { sftp, sess })
+        Ok(SftpSync {
+            sftp,
+            sess,
+            tools: OnceLock::new(),
+        })
     }
 
-    pub fn create_folder(&self, path: &Path) {
-        match self.sftp.mkdir(path, 10) {
-            Ok(_) => {}
-            Err(err) => {
-                match err.code() {
-                    ErrorCode::SFTP(num) => {
-                        if num == 4 {
-                            //do nothing just trying to create an exisiting folder
-                        } else {
-                            eprintln!("error creating folder {:?} \n {:?}", path, err)
-                        }
-                    }
-                    ErrorCode::Session(_) => {
-                        eprintln!("error creating folder {:?} \n {:?}", path, err)
-                    }
-                }
+    ///open a new session and start the sftp subsystem on it
+    pub fn connect(ssh: &SshCred) -> Result<Self, Error> {
+        let sess = ssh.connect()?;
+        SftpSync::new(sess).map_err(|source| Error::Connection {
+            addr: ssh.addr(),
+            source: source.into(),
+        })
+    }
+
+    //create a folder with exactly mode, the server umask is not applied
+    //returns the stat of the folder when it already existed
+    fn make_folder(&self, path: &Path, mode: u32) -> Result<Option<FileStat>, Error> {
+        match self.sftp.mkdir(path, mode as i32) {
+            Ok(_) => self.set_mode(path, mode).map(|_| None),
+            //servers answer a generic failure when the folder already exists
+            Err(err) if err.code() == ErrorCode::SFTP(4) => match self.sftp.stat(path) {
+                Ok(stat) if stat.is_dir() => Ok(Some(stat)),
+                _ => Err(err).at(path),
+            },
+            Err(err) => Err(err).at(path),
+        }
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> Result<(), Error> {
+        let stat = FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: Some(mode),
+            atime: None,
+            mtime: None,
+        };
+        self.sftp.setstat(path, stat).at(path)
+    }
+
+    //create a folder, or fix its mode if it already exists with another one
+    pub fn create_folder(&self, path: &Path, mode: u32) -> Result<(), Error> {
+        if let Some(stat) = self.make_folder(path, mode)? {
+            if stat.perm.map(|perm| perm & 0o7777) != Some(mode) {
+                self.set_mode(path, mode)?;
             }
         }
+        Ok(())
+    }
+
+    //create a folder and any missing parent, folders already there are left alone
+    pub fn create_folder_all(&self, path: &Path, mode: u32) -> Result<(), Error> {
+        let mut current = PathBuf::new();
+        for component in path.components() {
+            current.push(component);
+            if current.has_root() && current.parent().is_none() {
+                continue;
+            }
+            self.make_folder(&current, mode)?;
+        }
+        Ok(())
     }
 
+    //write a file next to path and move it over path once complete,
+    //the server never holds a partial copy under the name of the file
+    //reader yields the whole file, its first offset bytes are already in the temp
+    //file from an interrupted upload and are only read
     pub fn create_file(
         &self,
         path: &Path,
-        size: &u64,
-        times: Option<(u64, u64)>,
-        buf: &[u8],
+        reader: &mut dyn Read,
+        offset: u64,
     ) -> Result<(), Error> {
-        let mut remote_file = self.sess.scp_send(path, 0o644, *size, times)?;
-        remote_file.write(buf)?;
-        // Close the channel and wait for the whole content to be tranferred
-
-        remote_file.send_eof()?;
-        remote_file.wait_eof()?;
-        remote_file.close()?;
-        remote_file.wait_close()?;
-        Ok(())
+        let temp = temp_path(path);
+        let mut remote_file = match offset {
+            0 => {
+                let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+                self.sftp.open_mode(&temp, flags, 0o644, OpenType::File)
+            }
+            _ => self
+                .sftp
+                .open_mode(&temp, OpenFlags::WRITE, 0o644, OpenType::File),
+        }
+        .at(&temp)?;
+        remote_file
+            .seek(SeekFrom::Start(offset))
+            .map_err(transfer_error(path))?;
+        copy_after(reader, offset, &mut remote_file).map_err(transfer_error(path))?;
+        //not every server supports fsync
+        remote_file.fsync().ok();
+        drop(remote_file);
+        //a replaced file keeps its mode
+        if let Ok(FileStat {
+            perm: Some(perm), ..
+        }) = self.sftp.stat(path)
+        {
+            self.set_mode(&temp, perm & 0o7777)?;
+        }
+        self.replace(&temp, path)
     }
 
-    pub fn _remove_dir(&self, path: &Path) -> Result<(), Error> {
-        self.sftp.rmdir(path)?;
+    //bytes of an interrupted upload of local to path that are on the server and match it
+    pub fn uploaded_prefix(
+        &self,
+        path: &Path,
+        local: &Path,
+        local_len: u64,
+        events: Events,
+    ) -> Result<u64, Error> {
+        let temp = temp_path(path);
+        let len = match self.sftp.stat(&temp) {
+            Ok(FileStat {
+                size: Some(len), ..
+            }) if len <= local_len => len,
+            _ => return Ok(0),
+        };
+        if len == 0 {
+            return Ok(0);
+        }
+        match self.prefix_matches(&temp, local, len)? {
+            Some(true) => Ok(len),
+            Some(false) => Ok(0),
+            None => {
+                events.emit(SyncEvent::Fallback {
+                    path: local,
+                    reason: NO_PREFIX_HASH,
+                });
+                Ok(0)
+            }
+        }
+    }
 
-        Ok(())
+    //bytes of an interrupted download of path that are in temp and match it
+    fn downloaded_prefix(&self, path: &Path, temp: &Path, events: Events) -> Result<u64, Error> {
+        let len = match fs::metadata(temp) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return Ok(0),
+        };
+        if len == 0 || len > self.file_size(path)? {
+            return Ok(0);
+        }
+        match self.prefix_matches(path, temp, len)? {
+            Some(true) => Ok(len),
+            Some(false) => Ok(0),
+            None => {
+                events.emit(SyncEvent::Fallback {
+                    path,
+                    reason: NO_PREFIX_HASH,
+                });
+                Ok(0)
+            }
+        }
+    }
+
+    //whether the first len bytes of a remote and a local file are the same
+    //they are hashed on the server, None when it has no command for it
+    fn prefix_matches(&self, remote: &Path, local: &Path, len: u64) -> Result<Option<bool>, Error> {
+        for hash in [HashAlgorithm::Blake3, HashAlgorithm::Sha256] {
+            //the pipeline reports the status of the hash command, so a file head can't
+            //read is told apart first
+            let command = format!(
+                "[ -r {path} ] || exit {}; head -c {} -- {path} | {}",
+                UNREADABLE_STATUS,
+                len,
+                hash.command().unwrap(),
+                path = shell_quote(remote),
+            );
+            let output = match self.exec(&command, remote) {
+                Ok((output, 0)) => output,
+                Ok((_, 126 | 127)) | Err(_) => continue,
+                //head can't read the file
+                Ok((_, UNREADABLE_STATUS)) => return Ok(Some(false)),
+                Ok(_) => return Ok(Some(false)),
+            };
+            let remote_hash = output
+                .split_whitespace()
+                .next()
+                .map(|hex| hash.value_of_hex(hex));
+            return Ok(Some(
+                remote_hash == Some(prefix_checksum(local, len, hash)?),
+            ));
+        }
+        //reading the whole prefix costs as much as sending it again
+        Ok(None)
+    }
+
+    //move a complete upload over its target, atomically when the server allows it
+    fn replace(&self, temp: &Path, path: &Path) -> Result<(), Error> {
+        let flags = RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE;
+        let err = match self.sftp.rename(temp, path, Some(flags)) {
+            Ok(_) => return Ok(()),
+            Err(err) => err,
+        };
+        //sftp v3 servers don't rename over an existing file, rename(2) through mv does
+        let command = format!("mv -f -- {} {}", shell_quote(temp), shell_quote(path));
+        if let Ok((_, 0)) = self.exec(&command, path) {
+            return Ok(());
+        }
+        //without a shell the old copy is moved aside first and put back when the new one
+        //can't take its place, the file is missing for a moment but never partial or lost
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let aside = path.with_file_name(format!(".{}.old{}", name, TEMP_SUFFIX));
+        self.sftp.unlink(&aside).ok();
+        match self.sftp.rename(path, &aside, Some(flags)) {
+            Ok(_) => {}
+            //nothing to replace, the rename failed for another reason
+            Err(rename) if rename.code() == ErrorCode::SFTP(2) => return Err(err).at(path),
+            Err(rename) => return Err(rename).at(path),
+        }
+        if let Err(rename) = self.sftp.rename(temp, path, Some(flags)) {
+            self.sftp.rename(&aside, path, Some(flags)).ok();
+            return Err(rename).at(path);
+        }
+        self.sftp.unlink(&aside).at(&aside)
+    }
+
+    pub fn file_size(&self, path: &Path) -> Result<u64, Error> {
+        let stat = self.sftp.stat(path).at(path)?;
+        Ok(stat.size.unwrap_or(0))
+    }
+
+    //commands found on the server, path is only for error reporting
+    fn tools(&self, path: &Path) -> Result<RemoteTools, Error> {
+        if let Some(tools) = self.tools.get() {
+            return Ok(*tools);
+        }
+        let (output, _) = self.exec(TOOLS_PROBE, path)?;
+        let found = |name| output.lines().any(|line| line == name);
+        let tools = RemoteTools {
+            b3sum: found("b3sum"),
+            dd_bytes: found("dd"),
+            cp_preserve: found("cp"),
+        };
+        Ok(*self.tools.get_or_init(|| tools))
+    }
+
+    //whether the file at path is still the copy last uploaded, either its size and mtime
+    //didn't move since or it still hashes to it with `b3sum`
+    //false when the server has no `b3sum`, local is the file events are about
+    pub fn is_uploaded_copy(
+        &self,
+        path: &Path,
+        signature: &Signature,
+        local: &Path,
+        events: Events,
+    ) -> Result<bool, Error> {
+        let stat = match self.sftp.stat(path) {
+            Ok(stat) if stat.size == Some(signature.file_len) => stat,
+            _ => return Ok(false),
+        };
+        if signature.remote_stamp.is_some() && signature.remote_stamp == remote_stamp(&stat) {
+            return Ok(true);
+        }
+        if !self.tools(path)?.b3sum {
+            events.emit(SyncEvent::Fallback {
+                path: local,
+                reason: "no b3sum on the server to check its copy, sending the whole file",
+            });
+            return Ok(false);
+        }
+        let command = format!("b3sum -- {} 2>/dev/null", shell_quote(path));
+        let expected = blake3::Hash::from(signature.file_hash).to_hex();
+        match self.exec(&command, path)? {
+            (output, 0) => Ok(output.split_whitespace().next() == Some(expected.as_str())),
+            _ => Ok(false),
+        }
+    }
+
+    //rebuild the remote file from the delta of its local version in a copy made on the
+    //server: moved blocks are copied there, changed ranges written, the copy resized to the
+    //local length and moved over the remote file
+    //returns the bytes sent, None when the server can't copy the file, nothing was sent then
+    pub fn write_blocks(
+        &self,
+        path: &Path,
+        local: &Path,
+        delta: &Delta,
+        len: u64,
+        events: Events,
+    ) -> Result<Option<u64>, Error> {
+        let tools = self.tools(path)?;
+        let temp = temp_path(path);
+        let command = format!("cp -p -- {} {}", shell_quote(path), shell_quote(&temp));
+        if !tools.cp_preserve || !matches!(self.exec(&command, path), Ok((_, 0))) {
+            events.emit(SyncEvent::Fallback {
+                path: local,
+                reason: "the server can't copy its file with cp -p, sending the whole file",
+            });
+            return Ok(None);
+        }
+        let mut changed = delta.changed.clone();
+        let moved = !delta.moved.is_empty();
+        if moved && !(tools.dd_bytes && self.copy_moved(path, &temp, &delta.moved)?) {
+            //blocks the server couldn't move are sent like changed ones
+            events.emit(SyncEvent::Fallback {
+                path: local,
+                reason: "the server's dd can't copy by byte offset, sending moved blocks again",
+            });
+            changed.extend(delta.moved.iter().map(|m| m.to.clone()));
+            changed.sort_by_key(|range| range.start);
+        }
+        let mut local_file = fs::File::open(local).at(local)?;
+        let mut remote_file = self
+            .sftp
+            .open_mode(&temp, OpenFlags::WRITE, 0o644, OpenType::File)
+            .at(&temp)?;
+        let mut sent = 0;
+        for range in changed {
+            local_file.seek(SeekFrom::Start(range.start)).at(local)?;
+            remote_file
+                .seek(SeekFrom::Start(range.start))
+                .map_err(transfer_error(path))?;
+            let mut reader = ProgressReader::new(
+                (&mut local_file).take(range.end - range.start),
+                local,
+                sent,
+                events,
+            );
+            copy_chunked(&mut reader, &mut remote_file).map_err(transfer_error(path))?;
+            sent = reader.transferred();
+        }
+        remote_file
+            .setstat(FileStat {
+                size: Some(len),
+                uid: None,
+                gid: None,
+                perm: None,
+                atime: None,
+                mtime: None,
+            })
+            .at(&temp)?;
+        remote_file.fsync().ok();
+        drop(remote_file);
+        self.replace(&temp, path)?;
+        Ok(Some(sent))
+    }
+
+    //copy runs of blocks from the remote file to their new offsets in temp on the server
+    //returns false when its dd can't seek by bytes, temp may be partly written then
+    fn copy_moved(&self, path: &Path, temp: &Path, moved: &[Moved]) -> Result<bool, Error> {
+        for chunk in moved.chunks(COPY_BATCH) {
+            let line = chunk
+                .iter()
+                .map(|m| {
+                    format!(
+                        "dd if={} of={} bs=64K iflag=skip_bytes,count_bytes oflag=seek_bytes \
+                         conv=notrunc skip={} seek={} count={} 2>/dev/null",
+                        shell_quote(path),
+                        shell_quote(temp),
+                        m.from,
+                        m.to.start,
+                        m.to.end - m.to.start
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(" && ");
+            if self.exec(&line, path)?.1 != 0 {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    //run a shell command on the server, returns its output and exit status
+    //path is the file the command is about, for error reporting
+    fn exec(&self, command: &str, path: &Path) -> Result<(String, i32), Error> {
+        let mut channel = self.sess.channel_session().at(path)?;
+        channel.exec(command).at(path)?;
+        let mut output = String::new();
+        channel
+            .read_to_string(&mut output)
+            .map_err(transfer_error(path))?;
+        channel.wait_close().at(path)?;
+        Ok((output, channel.exit_status().at(path)?))
+    }
+
+    //remove a directory and everything in it
+    //sftp rmdir only works on empty directories so
+    //files are unlinked first and sub directories removed depth first
+    pub fn remove_dir_all(&self, path: &Path) -> Result<(), Error> {
+        let file_list = match self.sftp.readdir(path) {
+            Ok(file_list) => file_list,
+            //folder is already gone on the server
+            Err(err) if err.code() == ErrorCode::SFTP(2) => return Ok(()),
+            Err(err) => return Err(err).at(path),
+        };
+        for (item, stat) in file_list {
+            if stat.is_dir() {
+                self.remove_dir_all(&item)?;
+            } else {
+                self.sftp.unlink(&item).at(&item)?;
+            }
+        }
+        self.sftp.rmdir(path).at(path)
+    }
+
+    //create a link at path pointing to target, replacing whatever is there
+    pub fn create_link(&self, path: &Path, target: &Path) -> Result<(), Error> {
+        match self.sftp.unlink(path) {
+            Err(err) if err.code() != ErrorCode::SFTP(2) => return Err(err).at(path),
+            _ => {}
+        }
+        self.sftp.symlink(target, path).at(path)
     }
 
     pub fn remove_file(&self, path: &Path) -> Result<(), Error> {
-        self.sftp.unlink(path)?;
-        Ok(())
+        self.sftp.unlink(path).at(path)
+    }
+
+    pub fn download_item(
+        &self,
+        src: &Path,
+        dest: &Path,
+        events: Events,
+        options: &SyncOptions,
+    ) -> Result<(), Error> {
+        let root = self.sftp.realpath(src).at(src)?;
+        fs::create_dir_all(dest).at(dest)?;
+        create_checksum_file(dest)?;
+        let config = OpenConfig::open(dest)?;
+        let mut clone = Clone {
+            dest,
+            manifest: config.local(),
+            events,
+            options,
+            visited: HashSet::from([root.clone()]),
+            root,
+        };
+        self.download_folder(src, &mut clone)?;
+        config.close()
     }
 
-    pub fn download_item(&self, src: &Path, dest: &Path) -> Result<(), Error> {
+    fn download_folder(&self, src: &Path, clone: &mut Clone) -> Result<(), Error> {
+        let dest = clone.dest;
         let path = Path::new("").join(dest).join(src);
-        match fs::create_dir_all(&path) {
-            Ok(_) => {
-                create_checksum_file(&dest)?;
-                update_folder_config(
-                    "folders",
-                    &dest,
-                    &FolderConfig::Add(src.to_str().unwrap().to_string(), "".to_string()),
-                )?;
-                let file_list = self.sftp.readdir(src)?;
-                for i in file_list {
-                    if i.1.is_dir() {
-                        self.download_item(Path::new(&i.0), dest)?
-                    } else {
-                        self.download_file(
-                            Path::new(&i.0),
-                            &Path::new("").join(dest).join(&i.0),
-                            Some(dest),
-                        )?
+        fs::create_dir_all(&path).at(&path)?;
+        clone.events.emit(SyncEvent::FolderCreated { path: &path });
+        update_folder_config(
+            "folders",
+            clone.manifest,
+            &FolderConfig::Add(src.to_str().unwrap().to_string(), "".to_string()),
+        )?;
+        let file_list = self.sftp.readdir(src).at(src)?;
+        for (item, stat) in file_list {
+            if is_state_path(&item) {
+                continue;
+            } else if stat.file_type().is_symlink() {
+                self.download_link(&item, clone)?
+            } else if stat.is_dir() {
+                self.download_folder(&item, clone)?
+            } else {
+                self.download_file(
+                    &item,
+                    &Path::new("").join(dest).join(&item),
+                    Some(clone.manifest),
+                    clone.events,
+                    clone.options,
+                )?
+            }
+        }
+        //after the content, writing it would change the folder times again
+        self.copy_stat_to_local(src, &path, clone.options.preserve)
+    }
+
+    //handle a remote link found while cloning a folder as the policy says
+    //links leading outside the cloned folder are skipped whatever the policy
+    fn download_link(&self, item: &Path, clone: &mut Clone) -> Result<(), Error> {
+        let dest = Path::new("").join(clone.dest).join(item);
+        match clone.options.symlinks {
+            SymlinkPolicy::Skip => {}
+            SymlinkPolicy::Follow => match self.sftp.realpath(item) {
+                Ok(real) if real.starts_with(&clone.root) => {
+                    let stat = self.sftp.stat(item).at(item)?;
+                    if !stat.is_dir() {
+                        return self.download_file(
+                            item,
+                            &dest,
+                            Some(clone.manifest),
+                            clone.events,
+                            clone.options,
+                        );
+                    }
+                    //every folder is only followed into once so links can't loop
+                    if clone.visited.insert(real) {
+                        return self.download_folder(item, clone);
+                    }
+                }
+                _ => {}
+            },
+            SymlinkPolicy::Recreate => {
+                let target = self.sftp.readlink(item).at(item)?;
+                let parent = item.parent().unwrap_or_else(|| Path::new("."));
+                let parent = self.sftp.realpath(parent).at(parent)?;
+                if let Some(target) = contained_link_target(&parent, &target, &clone.root) {
+                    if create_local_link(&target, &dest)? {
+                        return update_folder_config(
+                            "files",
+                            clone.manifest,
+                            &FolderConfig::Add(
+                                item.to_str().unwrap().to_string(),
+                                format!("{}{}", LINK_PREFIX, target.to_str().unwrap()),
+                            ),
+                        );
                     }
                 }
-            }
-            Err(err) => {
-                println!("{:?}", err);
             }
         }
-
+        clone.events.emit(SyncEvent::LinkSkipped { path: item });
         Ok(())
     }
 
@@ -103,36 +622,254 @@ impl SftpSync {
         &self,
         src: &Path,
         dest: &Path,
-        config_dest: Option<&Path>,
+        manifest: Option<Manifest>,
+        events: Events,
+        options: &SyncOptions,
     ) -> Result<(), Error> {
-        let (mut remote_file, stat) = self.sess.scp_recv(src)?;
-        println!(
-            "...download file of size {} to path {:?}",
-            stat.size(),
-            dest
+        let manifest = match manifest {
+            Some(manifest) => manifest,
+            None => {
+                return self
+                    .receive_file(src, dest, false, events, options)
+                    .map(|_| ())
+            }
+        };
+        //a download interrupted by an earlier clone resumes when the server copy didn't move
+        let stamp = remote_stamp(&self.sftp.stat(src).at(src)?);
+        let mut transfers = Transfers::load(manifest)?;
+        let resume = transfers.start_download(dest, stamp);
+        transfers.save(manifest)?;
+        let (checksum_data, _) = self.receive_file(src, dest, resume, events, options)?;
+        transfers.finish_download(dest);
+        transfers.save(manifest)?;
+        let key = String::from(src.to_str().unwrap());
+        let stamp = local_stamp(dest)?;
+        update_folder_config(
+            "files",
+            manifest,
+            &FolderConfig::AddFile(key, checksum_data, stamp),
+        )
+    }
+
+    //download a file and return its checksum and the number of bytes received
+    //the file is written next to dest and moved over it once complete, with resume
+    //a partial copy left there by an interrupted download is completed
+    //nothing is written to the config here so this can run on any worker
+    pub fn receive_file(
+        &self,
+        src: &Path,
+        dest: &Path,
+        resume: bool,
+        events: Events,
+        options: &SyncOptions,
+    ) -> Result<(String, u64), Error> {
+        let temp = temp_path(dest);
+        let offset = match resume {
+            true => self.downloaded_prefix(src, &temp, events)?,
+            false => 0,
+        };
+        //scp is used from the start of the file, sftp can start anywhere
+        let mut channel = None;
+        let (remote_file, size): (Box<dyn Read>, u64) = match offset {
+            0 => {
+                let (remote_file, stat) = self.sess.scp_recv(src).at(src)?;
+                let size = stat.size();
+                (Box::new(channel.insert(remote_file).take(size)), size)
+            }
+            _ => {
+                let mut remote_file = self.sftp.open(src).at(src)?;
+                let size = remote_file.stat().at(src)?.size.unwrap_or(0);
+                remote_file
+                    .seek(SeekFrom::Start(offset))
+                    .map_err(transfer_error(src))?;
+                let rest = remote_file.take(size.saturating_sub(offset));
+                (Box::new(rest), size)
+            }
+        };
+        events.emit(SyncEvent::FileStarted { path: src, size });
+        let mut local_file = match offset {
+            0 => fs::File::create(&temp),
+            _ => fs::File::options().append(true).open(&temp),
+        }
+        .at(&temp)?;
+        let prefix = fs::File::open(&temp).at(&temp)?.take(offset);
+        let mut reader = ChecksumReader::new(
+            prefix.chain(ProgressReader::new(remote_file, src, offset, events)),
+            options.hash,
         );
-        let mut contents = Vec::new();
-        remote_file.read_to_end(&mut contents)?;
-        fs::write(dest, &contents)?;
-        match config_dest {
-            Some(dest) => {
-                let checksum_data = create_checksum(&contents[..]);
-                update_folder_config(
-                    "files",
-                    &dest,
-                    &FolderConfig::Add(
-                        String::from(src.to_str().unwrap()),
-                        format!("{}", checksum_data),
-                    ),
-                )?;
-            }
-            None => {}
-        }
-
-        remote_file.send_eof().unwrap();
-        remote_file.wait_eof().unwrap();
-        remote_file.close().unwrap();
-        remote_file.wait_close().unwrap();
+        let bytes =
+            copy_after(&mut reader, offset, &mut local_file).map_err(transfer_error(src))?;
+        let checksum_data = reader.checksum();
+        drop(reader);
+        if let Some(channel) = &mut channel {
+            close_channel(channel, src)?;
+        }
+        drop(local_file);
+        fs::rename(&temp, dest).at(dest)?;
+        self.copy_stat_to_local(src, dest, options.preserve)?;
+        events.emit(SyncEvent::FileFinished { path: src, bytes });
+        Ok((checksum_data, bytes))
+    }
+
+    //set the metadata of a remote item selected by preserve on a local one
+    pub fn copy_stat_to_local(
+        &self,
+        remote: &Path,
+        local: &Path,
+        preserve: Preserve,
+    ) -> Result<(), Error> {
+        if !preserve.any() {
+            return Ok(());
+        }
+        let stat = self.sftp.stat(remote).at(remote)?;
+        apply_stat(local, &stat, preserve)
+    }
+
+    //set the metadata of a local item selected by preserve on a remote one
+    pub fn copy_stat_to_remote(
+        &self,
+        local: &Path,
+        remote: &Path,
+        preserve: Preserve,
+    ) -> Result<(), Error> {
+        if !preserve.any() {
+            return Ok(());
+        }
+        self.sftp
+            .setstat(remote, local_stat(local, preserve)?)
+            .at(remote)
+    }
+
+    //checksum of a remote file, read through without keeping it
+    pub fn checksum_file(&self, path: &Path, hash: HashAlgorithm) -> Result<String, Error> {
+        let (mut remote_file, stat) = self.sess.scp_recv(path).at(path)?;
+        let mut reader = ChecksumReader::new((&mut remote_file).take(stat.size()), hash);
+        copy_chunked(&mut reader, &mut io::sink()).map_err(transfer_error(path))?;
+        let checksum_data = reader.checksum();
+        close_channel(&mut remote_file, path)?;
+        Ok(checksum_data)
+    }
+
+    //hash remote files with a command run through the shell, values are as recorded in the
+    //toml config. None when the server has no command for the algorithm, files that couldn't
+    //be hashed are left out
+    pub fn remote_hashes(
+        &self,
+        paths: &[PathBuf],
+        hash: HashAlgorithm,
+    ) -> Result<Option<HashMap<PathBuf, String>>, Error> {
+        let command = match hash.command() {
+            Some(command) => command,
+            None => return Ok(None),
+        };
+        let mut hashes = HashMap::new();
+        for chunk in paths.chunks(HASH_BATCH) {
+            let mut line = command.to_string();
+            line.push_str(" --");
+            for path in chunk {
+                line.push(' ');
+                line.push_str(&shell_quote(path));
+            }
+            line.push_str(" 2>/dev/null");
+            let (output, status) = self.exec(&line, &chunk[0])?;
+            //the shell couldn't find or run the command
+            if status == 126 || status == 127 {
+                return Ok(None);
+            }
+            for row in output.lines() {
+                //names with special characters are printed escaped, those are left out
+                if let Some((hex, path)) = row.split_once("  ") {
+                    if !row.starts_with('\\') {
+                        hashes.insert(PathBuf::from(path), hash.value_of_hex(hex));
+                    }
+                }
+            }
+        }
+        Ok(Some(hashes))
+    }
+
+    //remove a folder only if nothing is left in it, returns whether it was removed
+    pub fn remove_dir_if_empty(&self, path: &Path) -> Result<bool, Error> {
+        match self.sftp.readdir(path) {
+            Ok(file_list) if file_list.is_empty() => {
+                self.sftp.rmdir(path).at(path)?;
+                Ok(true)
+            }
+            Ok(_) => Ok(false),
+            Err(err) if err.code() == ErrorCode::SFTP(2) => Ok(true),
+            Err(err) => Err(err).at(path),
+        }
+    }
+
+    //every folder and file under path on the server, path included
+    //sync state kept by rxsync is left out, links are followed as symlinks says when they lead
+    //under path and skipped otherwise, they are never recreated
+    pub fn list_all(
+        &self,
+        path: &Path,
+        symlinks: SymlinkPolicy,
+        events: Events,
+    ) -> Result<Vec<(PathBuf, FileStat)>, Error> {
+        let stat = self.sftp.stat(path).at(path)?;
+        let is_dir = stat.is_dir();
+        let mut items = vec![(path.to_path_buf(), stat)];
+        if is_dir {
+            let root = self.sftp.realpath(path).at(path)?;
+            let mut listing = Listing {
+                follow: symlinks == SymlinkPolicy::Follow,
+                events,
+                visited: HashSet::from([root.clone()]),
+                root,
+            };
+            self.list_dir(path, &mut items, &mut listing)?;
+        }
+        Ok(items)
+    }
+
+    fn list_dir(
+        &self,
+        path: &Path,
+        items: &mut Vec<(PathBuf, FileStat)>,
+        listing: &mut Listing,
+    ) -> Result<(), Error> {
+        for (item, stat) in self.sftp.readdir(path).at(path)? {
+            if item.ends_with(CHECKSUM_FILE) || is_state_path(&item) {
+                continue;
+            }
+            let stat = match stat.file_type().is_symlink() {
+                true => match self.followed_link(&item, listing)? {
+                    Some(stat) => stat,
+                    None => {
+                        listing.events.emit(SyncEvent::LinkSkipped { path: &item });
+                        continue;
+                    }
+                },
+                false => stat,
+            };
+            if stat.is_dir() {
+                items.push((item.clone(), stat));
+                self.list_dir(&item, items, listing)?;
+            } else if stat.is_file() {
+                items.push((item, stat));
+            }
+        }
         Ok(())
     }
+
+    //stat of what a remote link leads to when the listing follows it
+    //every folder is only followed into once so links can't loop
+    fn followed_link(&self, item: &Path, listing: &mut Listing) -> Result<Option<FileStat>, Error> {
+        if !listing.follow {
+            return Ok(None);
+        }
+        let real = match self.sftp.realpath(item) {
+            Ok(real) if real.starts_with(&listing.root) => real,
+            _ => return Ok(None),
+        };
+        let stat = self.sftp.stat(item).at(item)?;
+        match stat.is_dir() && !listing.visited.insert(real) {
+            true => Ok(None),
+            false => Ok(Some(stat)),
+        }
+    }
 }
@@ -0,0 +1,183 @@
+use serde::{Deserialize, Serialize};
+use ssh2::FileStat;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use crate::config::{temp_path, Manifest, Stamp};
+use crate::error::{Error, PathContext};
+use crate::sftp::SftpSync;
+
+//transfers started by the last run of a target that may not have finished
+const TRANSFERS_FILE: &str = "transfers.toml";
+
+//stamp of a remote file, None when the server doesn't give its size and modification time
+pub fn remote_stamp(stat: &FileStat) -> Option<Stamp> {
+    Some([stat.size?, stat.mtime?])
+}
+
+//the stamp the source of each transfer had when it started,
+//a rerun resumes the transfers whose source didn't move since
+#[derive(Deserialize, Serialize, Default, Debug, PartialEq)]
+pub struct Transfers {
+    //by remote path
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    uploads: BTreeMap<String, Stamp>,
+    //by local path
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    downloads: BTreeMap<String, Stamp>,
+}
+
+fn transfers_path(manifest: Manifest) -> PathBuf {
+    manifest.state_path(TRANSFERS_FILE)
+}
+
+//record transfers about to start, returns the ones that pick up an interrupted transfer
+//of the same source and the interrupted transfers that can't be resumed
+fn start(
+    recorded: &mut BTreeMap<String, Stamp>,
+    started: &[(PathBuf, Option<Stamp>)],
+) -> (HashSet<PathBuf>, Vec<PathBuf>) {
+    let previous = mem::take(recorded);
+    let mut resume = HashSet::new();
+    for (path, stamp) in started {
+        let key = path.to_str().unwrap();
+        if let Some(stamp) = stamp {
+            if previous.get(key) == Some(stamp) {
+                resume.insert(path.clone());
+            }
+            recorded.insert(key.to_string(), *stamp);
+        }
+    }
+    let leftovers = previous
+        .into_keys()
+        .map(PathBuf::from)
+        .filter(|path| !resume.contains(path))
+        .collect();
+    (resume, leftovers)
+}
+
+impl Transfers {
+    pub fn load(manifest: Manifest) -> Result<Self, Error> {
+        let path = transfers_path(manifest);
+        match fs::read_to_string(&path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Transfers::default()),
+            Err(err) => Err(err).at(&path),
+            Ok(data) => toml::from_str(&data).map_err(|err| Error::Manifest {
+                path,
+                message: err.to_string(),
+            }),
+        }
+    }
+
+    pub fn save(&self, manifest: Manifest) -> Result<(), Error> {
+        let path = transfers_path(manifest);
+        if self.uploads.is_empty() && self.downloads.is_empty() {
+            return match fs::remove_file(&path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err).at(&path),
+                _ => Ok(()),
+            };
+        }
+        let data = toml::to_string(self).map_err(|err| Error::Manifest {
+            path: path.clone(),
+            message: err.to_string(),
+        })?;
+        fs::create_dir_all(path.parent().unwrap()).at(&path)?;
+        fs::write(&path, data).at(&path)
+    }
+
+    //record the uploads to these remote paths before any is written
+    //temp files left on the server by uploads that can't be resumed are removed
+    pub fn start_uploads(
+        &mut self,
+        sftp_conn: &SftpSync,
+        started: &[(PathBuf, Option<Stamp>)],
+    ) -> HashSet<PathBuf> {
+        let (resume, leftovers) = start(&mut self.uploads, started);
+        for path in leftovers {
+            //most were renamed over their target already
+            sftp_conn.sftp.unlink(&temp_path(&path)).ok();
+        }
+        resume
+    }
+
+    //record the downloads to these local paths before any is written
+    //partial copies left by downloads that can't be resumed are removed
+    pub fn start_downloads(&mut self, started: &[(PathBuf, Option<Stamp>)]) -> HashSet<PathBuf> {
+        let (resume, leftovers) = start(&mut self.downloads, started);
+        for path in leftovers {
+            fs::remove_file(temp_path(&path)).ok();
+        }
+        resume
+    }
+
+    //record a single download, other ones are left as they are
+    //returns whether it picks up an interrupted download
+    pub fn start_download(&mut self, local: &Path, stamp: Option<Stamp>) -> bool {
+        let key = local.to_str().unwrap().to_string();
+        match stamp {
+            Some(stamp) => self.downloads.insert(key, stamp) == Some(stamp),
+            None => {
+                self.downloads.remove(&key);
+                false
+            }
+        }
+    }
+
+    pub fn finish_uploads(&mut self) {
+        self.uploads.clear();
+    }
+
+    pub fn finish_downloads(&mut self) {
+        self.downloads.clear();
+    }
+
+    pub fn finish_download(&mut self, local: &Path) {
+        self.downloads.remove(local.to_str().unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transfers() {
+        let root = Path::new("test_transfers");
+        fs::create_dir_all(root).unwrap();
+        let manifest = Manifest::local(root);
+        let mut transfers = Transfers::default();
+        let started = [
+            (PathBuf::from("root/big"), Some([100, 1])),
+            (PathBuf::from("root/edited"), Some([10, 1])),
+            (PathBuf::from("root/no_mtime"), None),
+        ];
+        let (resume, leftovers) = start(&mut transfers.downloads, &started);
+        assert!(resume.is_empty() && leftovers.is_empty());
+        transfers.save(manifest).unwrap();
+
+        //the run was interrupted, root/edited changed and root/gone is no longer sent
+        let mut transfers = Transfers::load(manifest).unwrap();
+        transfers.downloads.insert("root/gone".to_string(), [5, 1]);
+        let started = [
+            (PathBuf::from("root/big"), Some([100, 1])),
+            (PathBuf::from("root/edited"), Some([12, 2])),
+        ];
+        let (resume, leftovers) = start(&mut transfers.downloads, &started);
+        assert_eq!(resume, HashSet::from([PathBuf::from("root/big")]));
+        assert_eq!(
+            leftovers,
+            vec![PathBuf::from("root/edited"), PathBuf::from("root/gone")]
+        );
+        assert!(transfers.start_download(Path::new("root/big"), Some([100, 1])));
+        assert!(!transfers.start_download(Path::new("root/new"), Some([1, 1])));
+
+        transfers.finish_downloads();
+        transfers.save(manifest).unwrap();
+        let finished = Transfers::load(manifest);
+        fs::remove_dir_all(root).unwrap();
+        assert_eq!(finished.unwrap(), Transfers::default());
+    }
+}
@@ -0,0 +1,585 @@
+use serde::{Deserialize, Serialize};
+use ssh2::FileStat;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::*;
+use crate::connection::SshCred;
+use crate::delta;
+use crate::error::{Error, PathContext};
+use crate::events::{Events, SyncEvent};
+use crate::file_util::*;
+use crate::options::{ConflictPolicy, HashAlgorithm, SymlinkPolicy, SyncOptions};
+use crate::plan::{Action, PlanItem, SyncPlan};
+use crate::pool::SftpPool;
+use crate::pull::ignore_not_found;
+use crate::report::{SyncReport, TwoWayReport};
+use crate::sftp::{SftpSync, DEFAULT_DIR_MODE};
+use crate::transfer::{remote_stamp, Transfers};
+
+pub(crate) const REMOTE_STATE_FILE: &str = "remote.toml";
+const CONFLICT_SUFFIX: &str = ".remote";
+
+//size and modification time of each file on the server after the last two-way sync
+//a file whose stat still matches hasn't changed there and doesn't need to be read
+#[derive(Deserialize, Serialize, Default)]
+struct RemoteState {
+    files: HashMap<String, [u64; 2]>,
+}
+
+fn remote_state_path(manifest: Manifest) -> PathBuf {
+    manifest.state_path(REMOTE_STATE_FILE)
+}
+
+fn read_remote_state(manifest: Manifest) -> Result<RemoteState, Error> {
+    let path = remote_state_path(manifest);
+    match fs::read_to_string(&path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(RemoteState::default()),
+        Err(err) => Err(err).at(&path),
+        Ok(data) => toml::from_str(&data).map_err(|err| Error::Manifest {
+            path,
+            message: err.to_string(),
+        }),
+    }
+}
+
+//record the stat of every remote file tracked in the toml config
+fn write_remote_state(
+    manifest: Manifest,
+    remote_files: &BTreeMap<PathBuf, FileStat>,
+) -> Result<(), Error> {
+    let parsed_config = load_checksum_config(manifest)?;
+    let mut state = RemoteState::default();
+    for (key, stat) in remote_files {
+        let key = key.to_str().unwrap();
+        if let (true, Some(stat)) = (parsed_config.files.contains_key(key), remote_stamp(stat)) {
+            state.files.insert(key.to_string(), stat);
+        }
+    }
+    let path = remote_state_path(manifest);
+    let data = toml::to_string(&state).map_err(|err| Error::Manifest {
+        path: path.clone(),
+        message: err.to_string(),
+    })?;
+    fs::create_dir_all(path.parent().unwrap()).at(&path)?;
+    fs::write(&path, data).at(&path)
+}
+
+//files and folders under src on the server, keyed by their local path
+//the same way sync lays them out under dest_path
+fn list_remote(
+    sftp_conn: &SftpSync,
+    src: &Path,
+    dest_path: &Path,
+    ignore_files: &IgnoreRules,
+    symlinks: SymlinkPolicy,
+    events: Events,
+) -> Result<(BTreeMap<PathBuf, FileStat>, HashSet<PathBuf>), Error> {
+    let tree = match sftp_conn.list_all(&dest_path.join(src), symlinks, events) {
+        //nothing was synced yet
+        Err(err) if err.sftp_code() == Some(2) => vec![],
+        result => result?,
+    };
+    let mut files = BTreeMap::new();
+    let mut folders = HashSet::new();
+    for (path, stat) in tree {
+        let key = path.strip_prefix(dest_path).unwrap_or(&path).to_path_buf();
+        if ignore_files.is_ignored(&key, stat.is_dir()) {
+            continue;
+        }
+        if stat.is_dir() {
+            folders.insert(key);
+        } else {
+            files.insert(key, stat);
+        }
+    }
+    Ok((files, folders))
+}
+
+//where the server version of a conflicting file is kept
+fn conflict_copy(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap().to_os_string();
+    name.push(CONFLICT_SUFFIX);
+    path.with_file_name(name)
+}
+
+//folders of src holding the files of a plan that will be sent
+fn parents_of(plan: &SyncPlan, src: &Path) -> HashSet<PathBuf> {
+    plan.items
+        .iter()
+        .filter(|i| matches!(i.action, Action::CreateFile | Action::UpdateFile))
+        .flat_map(|i| i.path.ancestors().skip(1))
+        .filter(|dir| dir.starts_with(src))
+        .map(Path::to_path_buf)
+        .collect()
+}
+
+//which side a file is copied to
+#[derive(Debug, PartialEq, Eq)]
+enum Resolution {
+    Unchanged,
+    ToRemote,
+    ToLocal,
+    //both sides already agree, only the config needs updating
+    Record,
+    KeepBoth,
+    Conflict,
+}
+
+//decide from the last synced checksum what changed on each side
+fn resolve(local: Option<&String>, remote: Option<&String>, base: Option<&String>) -> Resolution {
+    match (local != base, remote != base) {
+        (false, false) => Resolution::Unchanged,
+        (true, false) => Resolution::ToRemote,
+        (false, true) => Resolution::ToLocal,
+        (true, true) if local == remote => Resolution::Record,
+        (true, true) => Resolution::Conflict,
+    }
+}
+
+fn apply_policy(policy: ConflictPolicy, local: bool, remote: bool) -> Resolution {
+    match policy {
+        ConflictPolicy::LocalWins => Resolution::ToRemote,
+        ConflictPolicy::RemoteWins => Resolution::ToLocal,
+        ConflictPolicy::KeepBoth if local && remote => Resolution::KeepBoth,
+        //deleted on one side and changed on the other, keep the changed one
+        ConflictPolicy::KeepBoth if local => Resolution::ToRemote,
+        ConflictPolicy::KeepBoth => Resolution::ToLocal,
+        ConflictPolicy::Abort => Resolution::Conflict,
+    }
+}
+
+//bring the server changes into the local folder, config keys are the local paths
+fn apply_local(
+    plan: &SyncPlan,
+    remote_files: &BTreeMap<PathBuf, FileStat>,
+    manifest: Manifest,
+    pool: &SftpPool,
+    events: Events,
+    options: &SyncOptions,
+) -> Result<SyncReport, Error> {
+    let mut report = SyncReport::default();
+    let mut create_folder: Vec<&PlanItem> = plan.with_action(Action::CreateFolder).collect();
+    create_folder.sort_by_key(|i| i.path.components().count());
+    for i in create_folder {
+        fs::create_dir_all(&i.path).at(&i.path)?;
+        update_folder_config(
+            "folders",
+            manifest,
+            &FolderConfig::Add(i.path.to_str().unwrap().to_string(), "".to_string()),
+        )?;
+        events.emit(SyncEvent::FolderCreated { path: &i.path });
+        report.folders_created.push(i.path.clone());
+    }
+
+    for i in plan.with_action(Action::DeleteFile) {
+        ignore_not_found(fs::remove_file(&i.path)).at(&i.path)?;
+        delta::remove_signature(manifest, &i.path)?;
+        update_folder_config(
+            "files",
+            manifest,
+            &FolderConfig::Remove(i.path.to_str().unwrap().to_string()),
+        )?;
+        events.emit(SyncEvent::Deleted { path: &i.path });
+        report.files_deleted.push(i.path.clone());
+    }
+
+    let receive_list: Vec<&PlanItem> = plan
+        .items
+        .iter()
+        .filter(|i| matches!(i.action, Action::CreateFile | Action::UpdateFile))
+        .collect();
+    //downloads interrupted by an earlier sync resume when the server copy didn't move
+    let started: Vec<(PathBuf, Option<Stamp>)> = receive_list
+        .iter()
+        .map(|i| {
+            (
+                i.path.clone(),
+                remote_files.get(&i.path).and_then(remote_stamp),
+            )
+        })
+        .collect();
+    let mut transfers = Transfers::load(manifest)?;
+    let resume = transfers.start_downloads(&started);
+    transfers.save(manifest)?;
+    pool.run(
+        receive_list,
+        |sftp_conn, i| {
+            if let Some(parent) = i.path.parent() {
+                fs::create_dir_all(parent).at(parent)?;
+            }
+            let resume = resume.contains(&i.path);
+            let received = sftp_conn.receive_file(&i.remote, &i.path, resume, events, options)?;
+            Ok((received, local_stamp(&i.path)?))
+        },
+        |i, ((checksum, bytes), stamp)| {
+            //the last uploaded signature no longer describes the remote copy
+            delta::remove_signature(manifest, &i.path)?;
+            let key = i.path.to_str().unwrap().to_string();
+            update_folder_config(
+                "files",
+                manifest,
+                &FolderConfig::AddFile(key, checksum, stamp),
+            )?;
+            report.bytes_transferred += bytes;
+            if i.action == Action::UpdateFile {
+                report.files_updated.push(i.path.clone());
+            } else {
+                report.files_created.push(i.path.clone());
+            }
+            Ok(())
+        },
+    )?;
+    transfers.finish_downloads();
+    transfers.save(manifest)?;
+    for i in plan.with_action(Action::CreateFolder) {
+        pool.primary()
+            .copy_stat_to_local(&i.remote, &i.path, options.preserve)?;
+    }
+    Ok(report)
+}
+
+pub fn run_two_way(
+    ssh: &SshCred,
+    src: &Path,
+    dest_path: &Path,
+    options: &SyncOptions,
+    events: Events,
+) -> Result<TwoWayReport, Error> {
+    if !check_if_dir(src)? {
+        let err = io::Error::new(io::ErrorKind::InvalidInput, "two-way sync needs a folder");
+        return Err(err).at(src);
+    }
+    create_checksum_file(src)?;
+    let target = target_key(ssh, dest_path);
+    let config = OpenConfig::open(src)?;
+    let manifest = config.remote(&target);
+    let parsed_config = load_checksum_config(manifest)?;
+    let ignore_files = get_ignore_file(src, &options.ignore)?;
+    let remote_state = read_remote_state(manifest)?;
+
+    //links are only copied as links one way, two-way sync leaves them out then
+    let symlinks = match options.symlinks {
+        SymlinkPolicy::Recreate => SymlinkPolicy::Skip,
+        policy => policy,
+    };
+    let tree = list_local(src, symlinks)?;
+    for link in &tree.skipped_links {
+        if !ignore_files.is_ignored(link, false) {
+            events.emit(SyncEvent::LinkSkipped { path: link });
+        }
+    }
+    let mut local_files = tree.files;
+    remove_ignored_path(&mut local_files, &ignore_files);
+    let mut local_folders = tree.folders;
+    remove_ignored_path(&mut local_folders, &ignore_files);
+    let local_files: HashSet<PathBuf> = local_files.into_iter().collect();
+    let local_folders: HashSet<PathBuf> = local_folders.into_iter().collect();
+
+    let pool = SftpPool::connect(ssh, options.workers)?;
+    let sftp_conn = pool.primary();
+    let (remote_files, remote_folders) =
+        list_remote(sftp_conn, src, dest_path, &ignore_files, symlinks, events)?;
+
+    //the toml config is the state both sides had after the last sync
+    let base_files: BTreeMap<PathBuf, String> = parsed_config
+        .files
+        .iter()
+        .map(|(key, entry)| (PathBuf::from(key), entry.checksum.clone()))
+        .filter(|(key, _)| key.starts_with(src))
+        .collect();
+    let base_folders: BTreeSet<PathBuf> = parsed_config
+        .folders
+        .keys()
+        .map(PathBuf::from)
+        .filter(|key| key.starts_with(src))
+        .collect();
+
+    //both sides are hashed the way the last synced version was recorded
+    let hash_of = |key: &PathBuf| {
+        base_files
+            .get(key)
+            .and_then(|base| HashAlgorithm::of(base))
+            .unwrap_or(options.hash)
+    };
+    //the server copy is only read when its stat moved since the last sync
+    let remote_moved = |key: &PathBuf, stat: &FileStat| {
+        !base_files.contains_key(key)
+            || remote_stamp(stat).is_none()
+            || remote_state.files.get(key.to_str().unwrap()) != remote_stamp(stat).as_ref()
+    };
+    //server copies that need reading are hashed there in one batch per algorithm,
+    //the ones it can't hash are downloaded through checksum_file below
+    let mut to_hash: HashMap<HashAlgorithm, Vec<PathBuf>> = HashMap::new();
+    for (key, stat) in &remote_files {
+        if remote_moved(key, stat) {
+            to_hash
+                .entry(hash_of(key))
+                .or_default()
+                .push(dest_path.join(key));
+        }
+    }
+    let mut remote_hashes = HashMap::new();
+    for (hash, paths) in to_hash {
+        if let Some(found) = sftp_conn.remote_hashes(&paths, hash)? {
+            remote_hashes.extend(found);
+        }
+    }
+
+    let mut push = SyncPlan::default();
+    let mut pull = SyncPlan::default();
+    let mut records = vec![];
+    let mut conflicts = vec![];
+    let keys: BTreeSet<&PathBuf> = local_files
+        .iter()
+        .chain(remote_files.keys())
+        .chain(base_files.keys())
+        .collect();
+    for key in keys {
+        let base = base_files.get(key);
+        let hash = hash_of(key);
+        //the local copy is only read when its stamp moved since the last sync
+        let stamp = match local_files.contains(key) {
+            true => local_stamp(key)?,
+            false => None,
+        };
+        let recorded_stamp = parsed_config
+            .files
+            .get(key.to_str().unwrap())
+            .and_then(|entry| entry.stamp);
+        let stamp_moved = stamp.is_none() || recorded_stamp != stamp;
+        let read_local = stamp_moved || options.checksum || base.is_none();
+        let local = match local_files.contains(key) {
+            true if !read_local => base.cloned(),
+            true => Some(checksum_file(key, hash)?),
+            false => None,
+        };
+        let stat = remote_files.get(key);
+        let remote_path = dest_path.join(key);
+        let remote = match stat {
+            None => None,
+            Some(stat) if !remote_moved(key, stat) => base.cloned(),
+            Some(_) => match remote_hashes.remove(&remote_path) {
+                Some(found) => Some(found),
+                None => Some(sftp_conn.checksum_file(&remote_path, hash)?),
+            },
+        };
+
+        let mut resolution = resolve(local.as_ref(), remote.as_ref(), base);
+        if resolution == Resolution::Conflict {
+            conflicts.push(key.clone());
+            resolution = apply_policy(options.conflict, local.is_some(), remote.is_some());
+        }
+        let remote_size = stat.and_then(|stat| stat.size).unwrap_or(0);
+        match resolution {
+            Resolution::Unchanged => {
+                //entries written with another hash are moved over once the file was read
+                let rehash = read_local && hash != options.hash;
+                match &local {
+                    Some(_) if rehash => {
+                        let checksum = checksum_file(key, options.hash)?;
+                        push.refreshed.push((key.clone(), checksum, stamp));
+                    }
+                    Some(local) if stamp_moved => {
+                        push.refreshed.push((key.clone(), local.clone(), stamp))
+                    }
+                    _ => {}
+                }
+                push.unchanged.push(key.clone())
+            }
+            Resolution::ToRemote if local.is_some() => {
+                let action = match stat {
+                    Some(_) => Action::UpdateFile,
+                    None => Action::CreateFile,
+                };
+                push.push(action, key.clone(), remote_path, get_file_size(key)?);
+            }
+            Resolution::ToRemote => push.push(Action::DeleteFile, key.clone(), remote_path, 0),
+            Resolution::ToLocal if stat.is_some() => {
+                let action = match local {
+                    Some(_) => Action::UpdateFile,
+                    None => Action::CreateFile,
+                };
+                pull.push(action, key.clone(), remote_path, remote_size);
+            }
+            Resolution::ToLocal => pull.push(Action::DeleteFile, key.clone(), remote_path, 0),
+            Resolution::Record if hash != options.hash && local.is_some() => {
+                let checksum = checksum_file(key, options.hash)?;
+                records.push((key.clone(), Some(checksum), stamp))
+            }
+            Resolution::Record => records.push((key.clone(), local, stamp)),
+            Resolution::KeepBoth => {
+                let copy = conflict_copy(key);
+                pull.push(
+                    Action::CreateFile,
+                    copy.clone(),
+                    remote_path.clone(),
+                    remote_size,
+                );
+                push.push(
+                    Action::UpdateFile,
+                    key.clone(),
+                    remote_path,
+                    get_file_size(key)?,
+                );
+                push.push(
+                    Action::CreateFile,
+                    copy.clone(),
+                    dest_path.join(&copy),
+                    remote_size,
+                );
+            }
+            Resolution::Conflict => {}
+        }
+    }
+    if options.conflict == ConflictPolicy::Abort && !conflicts.is_empty() {
+        return Err(Error::Conflict { paths: conflicts });
+    }
+
+    //a folder missing on one side was either created on the other or deleted here,
+    //deleted folders stay if a file is about to be written in them
+    let needed_remote = parents_of(&push, src);
+    let needed_local = parents_of(&pull, src);
+    let mut delete_local = vec![];
+    let mut delete_remote = vec![];
+    for dir in local_folders.difference(&remote_folders) {
+        if !base_folders.contains(dir) || needed_remote.contains(dir) {
+            push.push(Action::CreateFolder, dir.clone(), dest_path.join(dir), 0);
+        } else {
+            delete_local.push(dir.clone());
+        }
+    }
+    for dir in remote_folders.difference(&local_folders) {
+        if !base_folders.contains(dir) || needed_local.contains(dir) {
+            pull.push(Action::CreateFolder, dir.clone(), dest_path.join(dir), 0);
+        } else {
+            delete_remote.push(dir.clone());
+        }
+    }
+
+    let mut pulled = apply_local(&pull, &remote_files, manifest, &pool, events, options)?;
+    for (key, checksum, stamp) in &records {
+        delta::remove_signature(manifest, key)?;
+        let key = key.to_str().unwrap().to_string();
+        let action = match checksum {
+            Some(checksum) => FolderConfig::AddFile(key, checksum.clone(), *stamp),
+            None => FolderConfig::Remove(key),
+        };
+        update_folder_config("files", manifest, &action)?;
+    }
+    for dir in local_folders.intersection(&remote_folders) {
+        push.synced_folders.push(dir.clone());
+        if !base_folders.contains(dir) {
+            update_folder_config(
+                "folders",
+                manifest,
+                &FolderConfig::Add(dir.to_str().unwrap().to_string(), "".to_string()),
+            )?;
+        }
+    }
+    if !push.is_empty() {
+        let mode = options.dir_mode.unwrap_or(DEFAULT_DIR_MODE);
+        sftp_conn.create_folder_all(&dest_path.join(src), mode)?;
+    }
+    //the server copy of a conflict changed since it was last uploaded, so its signature
+    //is stale and a local version pushed over it is sent in full
+    for key in &conflicts {
+        delta::remove_signature(manifest, key)?;
+    }
+    let mut pushed = crate::apply_plan(&push, dest_path, manifest, &pool, events, options)?;
+
+    //folders go last, deepest first, and only once empty
+    delete_local.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    for dir in delete_local {
+        let empty = fs::read_dir(&dir).at(&dir)?.next().is_none();
+        if empty {
+            fs::remove_dir(&dir).at(&dir)?;
+            update_folder_config(
+                "folders",
+                manifest,
+                &FolderConfig::Remove(dir.to_str().unwrap().to_string()),
+            )?;
+            events.emit(SyncEvent::Deleted { path: &dir });
+            pulled.folders_deleted.push(dir);
+        }
+    }
+    delete_remote.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    for dir in delete_remote {
+        if sftp_conn.remove_dir_if_empty(&dest_path.join(&dir))? {
+            update_folder_config(
+                "folders",
+                manifest,
+                &FolderConfig::Remove(dir.to_str().unwrap().to_string()),
+            )?;
+            events.emit(SyncEvent::Deleted { path: &dir });
+            pushed.folders_deleted.push(dir);
+        }
+    }
+
+    //links were reported by the first listing
+    let (remote_files, _) = list_remote(
+        sftp_conn,
+        src,
+        dest_path,
+        &ignore_files,
+        symlinks,
+        Events(None),
+    )?;
+    write_remote_state(manifest, &remote_files)?;
+    config.close()?;
+    Ok(TwoWayReport {
+        pushed,
+        pulled,
+        conflicts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let (a, b) = (Some("1".to_string()), Some("2".to_string()));
+        assert_eq!(
+            resolve(a.as_ref(), a.as_ref(), a.as_ref()),
+            Resolution::Unchanged
+        );
+        assert_eq!(
+            resolve(b.as_ref(), a.as_ref(), a.as_ref()),
+            Resolution::ToRemote
+        );
+        assert_eq!(resolve(None, a.as_ref(), a.as_ref()), Resolution::ToRemote);
+        assert_eq!(
+            resolve(a.as_ref(), b.as_ref(), a.as_ref()),
+            Resolution::ToLocal
+        );
+        assert_eq!(resolve(a.as_ref(), None, None), Resolution::ToRemote);
+        assert_eq!(
+            resolve(b.as_ref(), b.as_ref(), a.as_ref()),
+            Resolution::Record
+        );
+        assert_eq!(resolve(None, None, a.as_ref()), Resolution::Record);
+        assert_eq!(resolve(b.as_ref(), None, a.as_ref()), Resolution::Conflict);
+        assert_eq!(resolve(a.as_ref(), b.as_ref(), None), Resolution::Conflict);
+
+        assert_eq!(
+            apply_policy(ConflictPolicy::KeepBoth, true, true),
+            Resolution::KeepBoth
+        );
+        assert_eq!(
+            apply_policy(ConflictPolicy::KeepBoth, false, true),
+            Resolution::ToLocal
+        );
+        assert_eq!(
+            apply_policy(ConflictPolicy::LocalWins, false, true),
+            Resolution::ToRemote
+        );
+        assert_eq!(
+            conflict_copy(Path::new("dir/notes.txt")),
+            PathBuf::from("dir/notes.txt.remote")
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::*;
+use crate::connection::SshCred;
+use crate::delta;
+use crate::error::{Error, PathContext};
+use crate::events::Events;
+use crate::options::{HashAlgorithm, SyncOptions};
+use crate::plan::{Action, SyncPlan};
+use crate::pool::SftpPool;
+use crate::report::DriftReport;
+use crate::sftp::SftpSync;
+
+//server copy of a recorded item next to what `.xsync.toml` says about it
+enum Found {
+    Missing,
+    Modified,
+    Matches,
+}
+
+//check every item of the toml config under src against the server
+pub fn find_drift(
+    sftp_conn: &SftpSync,
+    src: &Path,
+    dest_path: &Path,
+    parsed_config: &Config,
+) -> Result<DriftReport, Error> {
+    let mut report = DriftReport::default();
+    let mut folders: Vec<PathBuf> = parsed_config
+        .folders
+        .keys()
+        .map(PathBuf::from)
+        .filter(|key| key.starts_with(src))
+        .collect();
+    folders.sort();
+    let mut files: Vec<(PathBuf, &FileEntry)> = parsed_config
+        .files
+        .iter()
+        .map(|(key, entry)| (PathBuf::from(key), entry))
+        .filter(|(key, _)| key.starts_with(src))
+        .collect();
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for key in folders {
+        let found = match lstat(sftp_conn, &dest_path.join(&key))? {
+            None => Found::Missing,
+            Some(stat) if !stat.is_dir() => Found::Modified,
+            Some(_) => Found::Matches,
+        };
+        sort_found(&mut report, key, found);
+    }
+
+    //files whose size matches are hashed on the server, one command per algorithm
+    let mut to_hash: HashMap<HashAlgorithm, Vec<(PathBuf, String)>> = HashMap::new();
+    for (key, entry) in files {
+        let value = entry.checksum.clone();
+        let remote = dest_path.join(&key);
+        let stat = lstat(sftp_conn, &remote)?;
+        let found = match (stat, value.strip_prefix(LINK_PREFIX)) {
+            (None, _) => Found::Missing,
+            (Some(stat), Some(target)) if stat.file_type().is_symlink() => {
+                match sftp_conn.sftp.readlink(&remote).at(&remote)? == Path::new(target) {
+                    true => Found::Matches,
+                    false => Found::Modified,
+                }
+            }
+            (Some(stat), Some(_)) if !stat.file_type().is_symlink() => Found::Modified,
+            (Some(stat), _) if !stat.is_file() => Found::Modified,
+            (Some(stat), _) => {
+                let size = entry.stamp.map(|stamp| stamp[0]);
+                if size.is_some() && stat.size != size {
+                    Found::Modified
+                } else {
+                    match HashAlgorithm::of(&value) {
+                        Some(hash) => to_hash.entry(hash).or_default().push((key, value)),
+                        None => report.size_only.push(key),
+                    }
+                    continue;
+                }
+            }
+        };
+        sort_found(&mut report, key, found);
+    }
+
+    for (hash, entries) in to_hash {
+        let remote: Vec<PathBuf> = entries.iter().map(|(key, _)| dest_path.join(key)).collect();
+        match sftp_conn.remote_hashes(&remote, hash)? {
+            Some(hashes) => {
+                for ((key, value), remote) in entries.into_iter().zip(remote) {
+                    match hashes.get(&remote) {
+                        Some(found) if *found == value => {}
+                        Some(_) => report.modified.push(key),
+                        None => report.size_only.push(key),
+                    }
+                }
+            }
+            None => report
+                .size_only
+                .extend(entries.into_iter().map(|(key, _)| key)),
+        }
+    }
+    report.modified.sort();
+    report.size_only.sort();
+    Ok(report)
+}
+
+fn sort_found(report: &mut DriftReport, key: PathBuf, found: Found) {
+    match found {
+        Found::Missing => report.missing.push(key),
+        Found::Modified => report.modified.push(key),
+        Found::Matches => {}
+    }
+}
+
+//stat of a remote item without following links, None when there is nothing there
+fn lstat(sftp_conn: &SftpSync, path: &Path) -> Result<Option<ssh2::FileStat>, Error> {
+    match sftp_conn.sftp.lstat(path) {
+        Ok(stat) => Ok(Some(stat)),
+        Err(err) if err.code() == ssh2::ErrorCode::SFTP(2) => Ok(None),
+        Err(err) => Err(err).at(path),
+    }
+}
+
+pub fn run_verify(
+    ssh: &SshCred,
+    src: &Path,
+    dest_path: &Path,
+    options: &SyncOptions,
+    events: Events,
+) -> Result<DriftReport, Error> {
+    let config_dir = crate::config_dir(src)?;
+    if !checksum_file_path(config_dir).exists() {
+        let err = io::Error::new(io::ErrorKind::NotFound, "nothing was synced from here yet");
+        return Err(err).at(&checksum_file_path(config_dir));
+    }
+    let target = target_key(ssh, dest_path);
+    let config = OpenConfig::open(config_dir)?;
+    let manifest = config.remote(&target);
+    let parsed_config = load_checksum_config(manifest)?;
+    let pool = SftpPool::connect(ssh, options.workers)?;
+    let mut report = find_drift(pool.primary(), src, dest_path, &parsed_config)?;
+    if report.no_drift() {
+        config.close()?;
+        return Ok(report);
+    }
+
+    let drifted: BTreeSet<PathBuf> = report
+        .missing
+        .iter()
+        .chain(&report.modified)
+        .cloned()
+        .collect();
+    let plan = plan_repair(src, dest_path, manifest, &drifted, options)?;
+    report.repaired = crate::apply_plan(&plan, dest_path, manifest, &pool, events, options)?;
+    config.close()?;
+    Ok(report)
+}
+
+//forgetting the drifted items makes the sync send them again in full
+fn plan_repair(
+    src: &Path,
+    dest_path: &Path,
+    manifest: Manifest,
+    drifted: &BTreeSet<PathBuf>,
+    options: &SyncOptions,
+) -> Result<SyncPlan, Error> {
+    let parsed_config = load_checksum_config(manifest)?;
+    for key in drifted {
+        let name = key.to_str().unwrap().to_string();
+        let key_config = match parsed_config.folders.contains_key(&name) {
+            true => "folders",
+            false => "files",
+        };
+        delta::remove_signature(manifest, key)?;
+        update_folder_config(key_config, manifest, &FolderConfig::Remove(name))?;
+    }
+    //planned from the config held in memory, the removals are not written to the file yet
+    let (_, plan) = crate::plan_sync(src, dest_path, manifest.target, manifest.open, options)?;
+    Ok(only_drifted(plan, drifted))
+}
+
+//keep the uploads of the drifted items and the folders they need,
+//local changes made since the last sync are left for the next one
+fn only_drifted(plan: SyncPlan, drifted: &BTreeSet<PathBuf>) -> SyncPlan {
+    let needed =
+        |path: &Path| drifted.contains(path) || drifted.iter().any(|item| item.starts_with(path));
+    SyncPlan {
+        items: plan
+            .items
+            .into_iter()
+            .filter(|i| match i.action {
+                Action::CreateFolder => needed(&i.path),
+                Action::CreateFile | Action::UpdateFile => drifted.contains(&i.path),
+                Action::DeleteFile | Action::DeleteFolder => false,
+            })
+            .collect(),
+        ..SyncPlan::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_util::{checksum_file, local_stamp};
+    use std::fs;
+
+    #[test]
+    fn test_only_drifted() {
+        let mut plan = SyncPlan::default();
+        for (action, path) in [
+            (Action::CreateFolder, "root/gone"),
+            (Action::CreateFolder, "root/new"),
+            (Action::CreateFile, "root/gone/file"),
+            (Action::CreateFile, "root/new/file"),
+            (Action::UpdateFile, "root/edited"),
+            (Action::UpdateFile, "root/changed_here"),
+            (Action::DeleteFile, "root/removed_here"),
+        ] {
+            plan.push(action, PathBuf::from(path), PathBuf::from(path), 0);
+        }
+        plan.unchanged.push(PathBuf::from("root/same"));
+        let drifted = BTreeSet::from([
+            PathBuf::from("root/gone/file"),
+            PathBuf::from("root/edited"),
+        ]);
+        let plan = only_drifted(plan, &drifted);
+        assert_eq!(
+            plan.to_string(),
+            "~ root/edited (0 bytes)\n+ root/gone/\n+ root/gone/file (0 bytes)\n"
+        );
+    }
+
+    #[test]
+    fn test_plan_repair() {
+        let root = Path::new("test_plan_repair");
+        fs::create_dir_all(root).unwrap();
+        let file = root.join("drifted");
+        fs::write(&file, "synced").unwrap();
+        create_checksum_file(root).unwrap();
+        let config = OpenConfig::open(root).unwrap();
+        let manifest = config.remote("host:22:");
+        let checksum = checksum_file(&file, HashAlgorithm::Blake3).unwrap();
+        let stamp = local_stamp(&file).unwrap();
+        let folder = FolderConfig::Add("test_plan_repair/".to_string(), "".to_string());
+        update_folder_config("folders", manifest, &folder).unwrap();
+        let key = file.to_str().unwrap().to_string();
+        let action = FolderConfig::AddFile(key, checksum, stamp);
+        update_folder_config("files", manifest, &action).unwrap();
+
+        let drifted = BTreeSet::from([file.clone()]);
+        let plan = plan_repair(
+            root,
+            Path::new(""),
+            manifest,
+            &drifted,
+            &SyncOptions::default(),
+        );
+        drop(config);
+        fs::remove_dir_all(root).unwrap();
+
+        //the removal is only held in memory when planning, the file is still sent again
+        let plan = plan.unwrap();
+        assert_eq!(plan.to_string(), "+ test_plan_repair/drifted (6 bytes)\n");
+    }
+}